@@ -1,21 +1,45 @@
+import os
 import pathlib
+import re
+import subprocess
 
 import click
 
 
+def escape(arg):
+    """Escape an argument for shim-tool, to be kept as-is by the shim.
+    """
+    return re.sub(r'([{}%$])', r'\1\1', arg)
+
+
+def has_shim_key():
+    """Whether shims must be signed, like `auth::key_path` in shims.
+    """
+    if os.environ.get('PYTHONUP_SHIM_KEY'):
+        return True
+    return pathlib.Path(
+        os.environ['LOCALAPPDATA'], 'pythonup', 'shim.key',
+    ).exists()
+
+
 @click.command()
 @click.argument('base', type=click.Path(exists=True, file_okay=False))
 def main(base):
     instdir = pathlib.Path(base)
     shim = instdir.joinpath('cmd', 'pythonup.exe')
     print('Writing {}'.format(shim))
-    data = instdir.joinpath('lib', 'shims', 'shim.exe').read_bytes()
-    cmd = '\0'.join([
-        str(instdir.joinpath('lib', 'python', 'python.exe')), '-m', 'pythonup',
+    shimsdir = instdir.joinpath('lib', 'shims')
+    args = [
+        str(shimsdir.joinpath('shim-tool.exe')), 'create',
+        '--base', str(shimsdir.joinpath('shim.exe')), '--out', str(shim),
+    ]
+    if has_shim_key():
+        args.append('--sign')
+    args.extend([
+        '--', escape(str(instdir.joinpath('lib', 'python', 'python.exe'))),
+        '-m', 'pythonup',
     ])
-    data += bytes(reversed((cmd + '\n\n').encode('utf-8')))
-    with shim.open('wb') as f:
-        f.write(data)
+    subprocess.check_call(args)
 
 
 if __name__ == '__main__':
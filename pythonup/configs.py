@@ -1,13 +1,35 @@
 import json
+import os
 import pathlib
 
 
+def get_installation_path():
+    return pathlib.Path(__file__).with_name('installation.json')
+
+
 def get_value(key):
-    with pathlib.Path(__file__).with_name('installation.json').open() as f:
+    with get_installation_path().open() as f:
         data = json.load(f)
     return data[key]
 
 
+def get_shim_key_path():
+    """Where the shim's key is, like `auth::key_path` in shims.
+    """
+    path = os.environ.get('PYTHONUP_SHIM_KEY')
+    if path:
+        return pathlib.Path(path)
+    return pathlib.Path(
+        os.environ['LOCALAPPDATA'], 'pythonup', 'shim.key',
+    )
+
+
+def requires_signed_shims():
+    return bool(os.environ.get('PYTHONUP_SHIM_KEY')) or (
+        get_shim_key_path().exists()
+    )
+
+
 def get_directory(key):
     path = pathlib.Path(__file__).parent.joinpath(get_value(key))
     path.mkdir(parents=True, exist_ok=True)
@@ -30,6 +52,10 @@ def get_shim_path():
     return get_directory('shims_dir').joinpath('shim.exe')
 
 
+def get_shim_tool_path():
+    return get_directory('shims_dir').joinpath('shim-tool.exe')
+
+
 def get_conf_path():
     path = get_directory('base_dir').joinpath('config')
     path.touch(mode=0o644, exist_ok=True)
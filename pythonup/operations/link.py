@@ -2,8 +2,12 @@ import collections
 import enum
 import filecmp
 import itertools
+import pathlib
+import re
 import shutil
+import subprocess
 import sys
+import tempfile
 
 import click
 
@@ -52,17 +56,57 @@ def publish_file(source, target, *, overwrite, quiet):
     )
 
 
-def publish_shim(source, target, *, relink, overwrite, quiet):
+def escape_shim_arg(arg):
+    """Escape an argument for shim-tool, to be kept as-is.
+
+    Shims expand placeholders and environment variables in their commands, so
+    special characters in literal paths are doubled.
+    """
+    return re.sub(r'([{}%$])', r'\1\1', arg)
+
+
+def build_shim(cmds, *, resolve=None, series=None):
+    """Build a shim running the commands with shim-tool, and return it.
+
+    With `resolve`, the first command runs that path in the installation of
+    the first active version in `series` when launched, instead.
+    """
+    installation = configs.get_installation_path()
+    with tempfile.TemporaryDirectory() as tmp:
+        out = pathlib.Path(tmp, 'shim.exe')
+        args = [
+            str(configs.get_shim_tool_path()), 'create',
+            '--base', str(configs.get_shim_path()), '--out', str(out),
+            '--installation', escape_shim_arg(str(installation.resolve())),
+        ]
+        if configs.requires_signed_shims():
+            args.append('--sign')
+        for i, cmd in enumerate(cmds):
+            if i:
+                args.append('--then')
+            elif resolve:
+                args.extend(['--resolve', resolve, '--series', series])
+            args.append('--')
+            args.extend(escape_shim_arg(arg) for arg in cmd)
+        result = subprocess.run(
+            args, stdout=subprocess.PIPE, stderr=subprocess.STDOUT,
+        )
+        if result.returncode != 0:
+            raise OSError(result.stdout.decode('utf-8', 'replace').strip())
+        return out.read_bytes()
+
+
+def publish_shim(source, target, *, relink, overwrite, quiet,
+                 resolve=None, series=None):
     """Write a shim.
 
     A shim is an pre-compiled executable, with extra data appended to the end
     of it. The extra data contain what command(s) the shim should attempt to
-    execute when launched. Arguments are seperated by NULL characters, and
-    commands (if there are multiple) are seperated by line feeds. Two extra
-    line feeds signify the end of the command sequence.
+    execute when launched. They are written by shim-tool, shipped next to the
+    shim executable, so the format has one source of truth.
 
-    The extra data are encoded with UTF-8, and written *backwards* into the
-    executable. This makes it easier to read data out.
+    Shims with `resolve` find the active version to run when launched, so
+    they do not need to be written again when active versions change.
     """
     cmds = [[str(source.resolve(strict=True))]]
     if relink:
@@ -70,15 +114,19 @@ def publish_shim(source, target, *, relink, overwrite, quiet):
             sys.executable, '-m', 'pythonup',
             'link', '--all', '--overwrite=smart', '--no-user-friendly',
         ])
-    data = bytes(reversed(
-        ('\n'.join('\0'.join(args) for args in cmds) + '\n\n').encode('utf-8')
-    ))
+    try:
+        data = build_shim(cmds, resolve=resolve, series=series)
+    except OSError as e:
+        click.echo('WARNING: Failed to build {}.\n{}: {}'.format(
+            target.name, type(e).__name__, e,
+        ), err=True)
+        return False
 
     def comp():
-        return target.read_bytes().endswith(data)
+        return target.read_bytes() == data
 
     def write():
-        target.write_bytes(configs.get_shim_path().read_bytes() + data)
+        target.write_bytes(data)
 
     return safe_publish(
         target, quiet=quiet, overwrite=overwrite, comparer=comp, writer=write,
@@ -125,8 +173,24 @@ def collect_version_scripts(versions):
     return scripts, shims
 
 
+def major_command_series(name):
+    """The series a command like "pip3.exe" is for, or None.
+    """
+    match = re.match(r'^(?:python|pip)(\d+)\.exe$', name, re.IGNORECASE)
+    if match:
+        return match.group(1)
+    return None
+
+
 def activate(versions, *, overwrite=Overwrite.yes,
-             allow_empty=False, quiet=False):
+             allow_empty=False, quiet=False, refresh_dynamic=True):
+    """Publish scripts of the versions, and make them active.
+
+    `python3` and `pip3`-like shims find the active version when launched,
+    so they are only written if missing unless `refresh_dynamic` is set.
+    Other scripts are copied from the versions.
+    """
+    dynamic_overwrite = overwrite if refresh_dynamic else Overwrite.no
     if not allow_empty and not versions:
         click.echo('No active versions.', err=True)
         click.get_current_context().exit(1)
@@ -153,9 +217,19 @@ def activate(versions, *, overwrite=Overwrite.yes,
             if target in using_scripts:
                 continue
             using_scripts.add(target)
-            publish_shim(
-                source, target, relink=True, overwrite=overwrite, quiet=quiet,
-            )
+            series = major_command_series(source.name)
+            if series:
+                resolve = pathlib.PureWindowsPath('Scripts', source.name)
+                publish_shim(
+                    source, target, relink=True,
+                    overwrite=dynamic_overwrite, quiet=quiet,
+                    resolve=str(resolve), series=series,
+                )
+            else:
+                publish_shim(
+                    source, target, relink=True,
+                    overwrite=overwrite, quiet=quiet,
+                )
         for version in versions:
             target = version.python_major_command
             if target in using_scripts:
@@ -163,7 +237,8 @@ def activate(versions, *, overwrite=Overwrite.yes,
             using_scripts.add(target)
             publish_shim(
                 version.get_installation().python, target,
-                relink=False, overwrite=overwrite, quiet=quiet,
+                relink=False, overwrite=dynamic_overwrite, quiet=quiet,
+                resolve='python.exe', series=str(version.version_info[0]),
             )
 
     set_active_versions(versions)
@@ -254,7 +329,11 @@ def use(ctx, versions, add):
         click.echo('Using: {}'.format(', '.join(v.name for v in versions)))
     else:
         click.echo('Not using any versions.')
-    activate(versions, allow_empty=(not add))
+    # Shims for python3 and pip3 follow the configuration by themselves.
+    activate(
+        versions, overwrite=Overwrite.smart, allow_empty=(not add),
+        refresh_dynamic=False,
+    )
 
 
 def link(ctx, command, link_all, overwrite, user_friendly):
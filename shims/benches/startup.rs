@@ -0,0 +1,62 @@
+//! Compare the cost of reading shim data out of a large executable.
+//!
+//! Run with `cargo bench`. The legacy strategy reads the whole file into
+//! memory byte by byte and reverses it; the current one seeks from the end.
+
+extern crate shim;
+
+use std::env;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use shim::cmds::Commands;
+
+const FIXTURE_SIZE: usize = 8 * 1024 * 1024;
+const ITERATIONS: u32 = 20;
+
+fn write_fixture(path: &Path) -> io::Result<()> {
+    // Fill the "executable" with something that never forms a terminator.
+    let mut bytes: Vec<u8> = (0..FIXTURE_SIZE).map(|i| (i % 251) as u8 | 1)
+        .collect();
+    let data = "C:\\python.exe\0-OO\nC:\\python.exe\0-m\0pythonup\n\n";
+    bytes.extend(data.bytes().rev());
+    fs::write(path, bytes)
+}
+
+fn read_legacy(path: &Path) -> usize {
+    let file = File::open(path).unwrap();
+    let mut bytes: Vec<_> = io::BufReader::new(file).bytes().collect();
+    bytes.reverse();
+    let bytes = bytes.into_iter().map(|r| r.unwrap()).collect();
+    Commands::from(bytes).unwrap().count()
+}
+
+fn read_current(path: &Path) -> usize {
+    Commands::from_path(path).unwrap().count()
+}
+
+fn measure<F: Fn(&Path) -> usize>(name: &str, path: &Path, f: F) -> Duration {
+    assert_eq!(f(path), 2);
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        f(path);
+    }
+    let mean = start.elapsed() / ITERATIONS;
+    println!("{:>8}: {:>12?} per read", name, mean);
+    mean
+}
+
+fn main() {
+    let path = env::temp_dir().join("pythonup-shim-bench.exe");
+    write_fixture(&path).unwrap();
+
+    println!("{} MiB fixture, {} iterations", FIXTURE_SIZE >> 20, ITERATIONS);
+    let legacy = measure("legacy", &path, read_legacy);
+    let current = measure("current", &path, read_current);
+    println!("{:>8}: {:.1}x", "speedup",
+             legacy.as_secs_f64() / current.as_secs_f64());
+
+    fs::remove_file(&path).unwrap();
+}
@@ -0,0 +1,219 @@
+//! Authenticate shim data with the user's key.
+//!
+//! Shims usually live in a user-writable directory, so their data could be
+//! changed to run something else. If a key file exists, shims must end
+//! their payload with a `MAC` record, an HMAC-SHA256 of the records before
+//! it keyed with the file's content, or they refuse to run. Legacy shims
+//! cannot be signed, so refuse to run too.
+//!
+//! The key is looked up at a fixed location, never one recorded in the shim,
+//! so it cannot be redirected along with the data: `PYTHONUP_SHIM_KEY` if
+//! set, or `pythonup\shim.key` in `%LOCALAPPDATA%` (`$XDG_CONFIG_HOME` or
+//! `~/.config` on Unix). A key named by `PYTHONUP_SHIM_KEY` must exist.
+//!
+//! This does not protect against a shim replaced by another executable, nor
+//! against anyone who can read the key or remove it from its directory.
+
+#[cfg(windows)] extern crate winapi;
+
+use std::env;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+
+use digest::{hmac_sha256, DIGEST_SIZE};
+use errors::Error;
+use trailer::{self, tags, Record};
+
+pub const KEY_ENV: &str = "PYTHONUP_SHIM_KEY";
+pub const KEY_SIZE: usize = 32;
+
+// Tag and length of the MAC record, which ends a signed payload.
+const MAC_HEADER: [u8; 5] = [tags::MAC, DIGEST_SIZE as u8, 0, 0, 0];
+const MAC_RECORD_SIZE: usize = 5 + DIGEST_SIZE;
+
+#[cfg(windows)]
+fn default_key_dir() -> Option<PathBuf> {
+    env::var_os("LOCALAPPDATA").map(PathBuf::from)
+}
+
+#[cfg(not(windows))]
+fn default_key_dir() -> Option<PathBuf> {
+    match env::var_os("XDG_CONFIG_HOME") {
+        Some(ref dir) if !dir.is_empty() => Some(PathBuf::from(dir)),
+        _ => env::var_os("HOME").map(|home| Path::new(&home).join(".config")),
+    }
+}
+
+// The key named by the environment, which must exist.
+fn explicit_key_path() -> Option<PathBuf> {
+    match env::var_os(KEY_ENV) {
+        Some(ref path) if !path.is_empty() => Some(PathBuf::from(path)),
+        _ => None,
+    }
+}
+
+/// Where the user's key is.
+pub fn key_path() -> Option<PathBuf> {
+    explicit_key_path().or_else(|| {
+        default_key_dir().map(|dir| dir.join("pythonup").join("shim.key"))
+    })
+}
+
+/// Read a key file.
+pub fn read_key(path: &Path) -> io::Result<Vec<u8>> {
+    let mut key = vec![];
+    File::open(path)?.read_to_end(&mut key)?;
+    if key.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "empty key"));
+    }
+    Ok(key)
+}
+
+/// The key shims must be signed with, if any.
+///
+/// A key named by `PYTHONUP_SHIM_KEY` that cannot be read is an error, and
+/// so is one at the default location that exists but cannot be read.
+pub fn user_key() -> Result<Option<Vec<u8>>, Error> {
+    let explicit = explicit_key_path().is_some();
+    let path = match key_path() {
+        Some(path) => path,
+        None => { return Ok(None); },
+    };
+    match read_key(&path) {
+        Ok(key) => Ok(Some(key)),
+        Err(ref e) if !explicit && e.kind() == io::ErrorKind::NotFound => {
+            Ok(None)
+        },
+        Err(e) => Err(Error::Unauthenticated(
+            format!("failed to read key {}: {}", path.display(), e),
+        )),
+    }
+}
+
+/// Generate a random key.
+#[cfg(unix)]
+pub fn generate_key() -> io::Result<[u8; KEY_SIZE]> {
+    let mut key = [0u8; KEY_SIZE];
+    File::open("/dev/urandom")?.read_exact(&mut key)?;
+    Ok(key)
+}
+
+/// Generate a random key.
+#[cfg(windows)]
+pub fn generate_key() -> io::Result<[u8; KEY_SIZE]> {
+    use self::winapi::um::ntsecapi::RtlGenRandom;
+
+    let mut key = [0u8; KEY_SIZE];
+    let ok = unsafe {
+        RtlGenRandom(key.as_mut_ptr() as *mut _, KEY_SIZE as u32)
+    };
+    if ok == 0 {
+        return Err(io::Error::other("failed to generate random bytes"));
+    }
+    Ok(key)
+}
+
+/// Append a MAC of the records to them.
+pub fn sign(records: &mut Vec<Record>, key: &[u8]) {
+    let mac = hmac_sha256(key, &trailer::encode_payload(records));
+    records.push(Record { tag: tags::MAC, data: mac.to_vec() });
+}
+
+// Compare in constant time, to not tell how much of a forged MAC is right.
+fn same(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Split a payload into its records before the MAC, and the MAC, if any.
+pub fn split_mac(payload: &[u8]) -> (&[u8], Option<&[u8]>) {
+    if payload.len() >= MAC_RECORD_SIZE {
+        let (records, mac) = payload.split_at(payload.len() - MAC_RECORD_SIZE);
+        if mac[..5] == MAC_HEADER {
+            return (records, Some(&mac[5..]));
+        }
+    }
+    (payload, None)
+}
+
+/// Check a payload against its MAC, and return its records without it.
+///
+/// Without a key, anything is accepted. With one, the payload must be
+/// signed with it.
+pub fn authenticate<'a>(payload: &'a [u8], key: Option<&[u8]>)
+        -> Result<&'a [u8], Error> {
+    let (records, mac) = split_mac(payload);
+    let key = match key {
+        Some(key) => key,
+        None => { return Ok(records); },
+    };
+    match mac {
+        Some(mac) if same(mac, &hmac_sha256(key, records)) => Ok(records),
+        Some(_) => Err(Error::Unauthenticated(
+            String::from("signature does not match"),
+        )),
+        None => Err(Error::Unauthenticated(String::from("not signed"))),
+    }
+}
+
+
+#[cfg(test)]
+mod auth_test {
+    use trailer::{encode_payload, tags, Record};
+    use super::{authenticate, same, sign, split_mac};
+
+    fn records() -> Vec<Record> {
+        vec![
+            Record { tag: tags::COMMAND, data: vec![] },
+            Record { tag: tags::ARG, data: b"C:\\python.exe".to_vec() },
+        ]
+    }
+
+    fn signed(key: &[u8]) -> Vec<u8> {
+        let mut records = records();
+        sign(&mut records, key);
+        assert_eq!(records.last().unwrap().tag, tags::MAC);
+        encode_payload(&records)
+    }
+
+    #[test]
+    fn signed_payload() {
+        let unsigned = encode_payload(&records());
+        let payload = signed(b"key");
+        let key: Option<&[u8]> = Some(b"key");
+        assert_eq!(authenticate(&payload, key).unwrap(), &unsigned[..]);
+        assert_eq!(authenticate(&payload, None).unwrap(), &unsigned[..]);
+        assert!(authenticate(&payload, Some(b"other key")).is_err());
+    }
+
+    #[test]
+    fn tampered() {
+        let mut payload = signed(b"key");
+        payload[10] = b'D';
+        let e = authenticate(&payload, Some(b"key")).unwrap_err();
+        assert_eq!(e.exit_code(), 120);
+
+        // The MAC cannot be stripped, or moved before added records.
+        let unsigned = encode_payload(&records());
+        assert!(authenticate(&unsigned, Some(b"key")).is_err());
+        assert_eq!(authenticate(&unsigned, None).unwrap(), &unsigned[..]);
+        let mut extended = signed(b"key");
+        extended.extend(b"\x02\x01\x00\x00\x00x");
+        assert!(authenticate(&extended, Some(b"key")).is_err());
+    }
+
+    #[test]
+    fn mac_record() {
+        assert_eq!(split_mac(b""), (&b""[..], None));
+        let payload = signed(b"key");
+        let (records, mac) = split_mac(&payload);
+        assert_eq!(records.len() + 37, payload.len());
+        assert_eq!(mac.unwrap().len(), 32);
+        assert!(same(b"abc", b"abc"));
+        assert!(!same(b"abc", b"abd"));
+        assert!(!same(b"abc", b"ab"));
+    }
+}
@@ -1,21 +1,163 @@
-use std::env::current_exe;
+use std::cell::Cell;
+use std::env::{self, current_exe};
+use std::ffi::{OsStr, OsString};
 use std::fs::File;
-use std::io::{self, Read};
-use std::path::PathBuf;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
 use std::vec::IntoIter;
 
-#[derive(Debug)]
+use auth;
+use digest::DIGEST_SIZE;
+use errors::Error;
+use resolve;
+use shebang;
+use trailer::{self, tags, Record};
+use zipapp::{self, Location, Zipapp};
+
+// Size of each chunk read when scanning backwards for shim data.
+const CHUNK_SIZE: u64 = 512;
+
+/// A change to the environment of a command's process.
+#[derive(Clone, Debug, PartialEq)]
+pub enum EnvOp {
+    /// Set a variable to a value.
+    Set(OsString, OsString),
+
+    /// Remove a variable.
+    Unset(OsString),
+
+    /// Prepend an entry to a path-like variable, e.g. `PATH`. The variable
+    /// is set to the entry if it is not set yet.
+    PrependPath(OsString, OsString),
+}
+
+/// When a command runs, depending on the exit code of the last command run
+/// before it. The first command runs as if after a successful one.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Condition {
+    Always = 0,
+    #[default]
+    OnSuccess = 1,
+    OnFailure = 2,
+}
+
+impl Condition {
+    pub fn from_byte(byte: u8) -> Option<Self> {
+        match byte {
+            0 => Some(Condition::Always),
+            1 => Some(Condition::OnSuccess),
+            2 => Some(Condition::OnFailure),
+            _ => None,
+        }
+    }
+
+    /// Whether to run after a command exiting with `code`.
+    pub fn allows(self, code: i32) -> bool {
+        match self {
+            Condition::Always => true,
+            Condition::OnSuccess => code == 0,
+            Condition::OnFailure => code != 0,
+        }
+    }
+}
+
+/// Which exit code a shim reports, among the commands it runs.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Report {
+    /// The first command's.
+    First = 0,
+
+    /// The last command's.
+    #[default]
+    Last = 1,
+
+    /// The worst, i.e. zero only if all commands succeed. Codes are compared
+    /// as unsigned, so crashes on Windows (e.g. `0xC0000005`) are the worst.
+    Worst = 2,
+}
+
+impl Report {
+    pub fn from_byte(byte: u8) -> Option<Self> {
+        match byte {
+            0 => Some(Report::First),
+            1 => Some(Report::Last),
+            2 => Some(Report::Worst),
+            _ => None,
+        }
+    }
+
+    /// Choose the code to report, or zero if no commands ran.
+    pub fn choose(self, codes: &[i32]) -> i32 {
+        let code = match self {
+            Report::First => codes.first(),
+            Report::Last => codes.last(),
+            Report::Worst => codes.iter().max_by_key(|&&code| code as u32),
+        };
+        code.cloned().unwrap_or(0)
+    }
+}
+
+/// What to do if a command's executable changed since the shim was written.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum OnChange {
+    /// Print a warning, and run it anyway.
+    #[default]
+    Warn = 0,
+
+    /// Fail without running it.
+    Refuse = 1,
+}
+
+impl OnChange {
+    pub fn from_byte(byte: u8) -> Option<Self> {
+        match byte {
+            0 => Some(OnChange::Warn),
+            1 => Some(OnChange::Refuse),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
 pub struct Command {
     exe: Option<PathBuf>,
-    args: Vec<String>,
+    args: Vec<OsString>,
+    env: Vec<EnvOp>,
+    working_dir: Option<PathBuf>,
+    resolve: Option<PathBuf>,
+    series: Option<String>,
+    appends_own_args: bool,
+    condition: Condition,
+    detached: bool,
+    exe_digest: Option<[u8; DIGEST_SIZE]>,
+    prepends_installation: bool,
+}
+
+impl Default for Command {
+    fn default() -> Self {
+        Command {
+            exe: None,
+            args: vec![],
+            env: vec![],
+            working_dir: None,
+            resolve: None,
+            series: None,
+            appends_own_args: true,
+            condition: Condition::default(),
+            detached: false,
+            exe_digest: None,
+            prepends_installation: false,
+        }
+    }
 }
 
 impl Command {
     pub fn new() -> Self {
-        Command { exe: None, args: vec![] }
+        Self::default()
     }
 
-    pub fn arg(&mut self, arg: String) {
+    pub fn arg<S: Into<OsString>>(&mut self, arg: S) {
+        let arg = arg.into();
         match self.exe {
             Some(_) => self.args.push(arg),
             None => self.exe = Some(PathBuf::from(arg)),
@@ -26,14 +168,491 @@ impl Command {
         self.exe.as_ref()
     }
 
-    pub fn args(&self) -> &Vec<String> {
+    pub fn args(&self) -> &Vec<OsString> {
         &self.args
     }
+
+    /// Changes to the environment, applied in order.
+    pub fn env(&self) -> &Vec<EnvOp> {
+        &self.env
+    }
+
+    pub fn push_env(&mut self, op: EnvOp) {
+        self.env.push(op);
+    }
+
+    /// Directory to run the command in, instead of the shim's.
+    pub fn working_dir(&self) -> Option<&PathBuf> {
+        self.working_dir.as_ref()
+    }
+
+    pub fn set_working_dir<P: Into<PathBuf>>(&mut self, path: P) {
+        self.working_dir = Some(path.into());
+    }
+
+    /// Path of the executable relative to an installation directory.
+    ///
+    /// If set, the executable is looked up at launch in the installation of
+    /// the version pinned for the working directory, and `exe` is only used
+    /// if no versions are pinned.
+    pub fn resolve(&self) -> Option<&PathBuf> {
+        self.resolve.as_ref()
+    }
+
+    pub fn set_resolve<P: Into<PathBuf>>(&mut self, path: P) {
+        self.resolve = Some(path.into());
+    }
+
+    /// Version series the command is for, e.g. `3` for `python3`.
+    ///
+    /// When resolving against active versions, only those in the series are
+    /// considered. Pinned versions are used regardless.
+    pub fn series(&self) -> Option<&str> {
+        self.series.as_deref()
+    }
+
+    pub fn set_series<S: Into<String>>(&mut self, series: S) {
+        self.series = Some(series.into());
+    }
+
+    /// Whether the shim's own arguments may be appended to the command.
+    ///
+    /// This is `true` unless disabled explicitly, or the arguments are
+    /// placed in the command with `{args}` or `{argN}` already. The shim
+    /// only appends them to its first command.
+    pub fn appends_own_args(&self) -> bool {
+        self.appends_own_args
+    }
+
+    pub fn set_appends_own_args(&mut self, value: bool) {
+        self.appends_own_args = value;
+    }
+
+    pub fn condition(&self) -> Condition {
+        self.condition
+    }
+
+    pub fn set_condition(&mut self, condition: Condition) {
+        self.condition = condition;
+    }
+
+    /// Whether the command is started in the background.
+    ///
+    /// The shim does not wait for a detached command, and its exit code is
+    /// not reported. Only one instance of the same command runs at a time.
+    pub fn detached(&self) -> bool {
+        self.detached
+    }
+
+    pub fn set_detached(&mut self, value: bool) {
+        self.detached = value;
+    }
+
+    /// SHA-256 of the executable when the shim was written.
+    ///
+    /// If set, the executable is checked against it before running, unless
+    /// resolved to another one.
+    pub fn exe_digest(&self) -> Option<&[u8; DIGEST_SIZE]> {
+        self.exe_digest.as_ref()
+    }
+
+    pub fn set_exe_digest(&mut self, digest: [u8; DIGEST_SIZE]) {
+        self.exe_digest = Some(digest);
+    }
+
+    /// Whether the child's `PATH` leads to the executable's installation.
+    ///
+    /// If set, the installation directory and its `Scripts` are prepended
+    /// to `PATH`, and the shim's directory is removed from it, so e.g.
+    /// `python3` run by an interpreter is the same interpreter, not a shim.
+    pub fn prepends_installation(&self) -> bool {
+        self.prepends_installation
+    }
+
+    pub fn set_prepends_installation(&mut self, value: bool) {
+        self.prepends_installation = value;
+    }
+}
+
+/// How deeply shims may be nested, unless configured otherwise.
+pub const DEFAULT_MAX_DEPTH: u8 = 16;
+
+/// Options affecting all commands in a shim.
+#[derive(Debug, Default, PartialEq)]
+pub struct Options {
+    versions_dir: Option<PathBuf>,
+    installation: Option<PathBuf>,
+    report: Report,
+    watch_dir: Option<PathBuf>,
+    on_change: OnChange,
+    max_depth: Option<u8>,
+    script: Option<PathBuf>,
+    shebang: Option<OsString>,
+    zipapp: Option<Zipapp>,
+}
+
+impl Options {
+    /// Directory containing installations, each named after its version.
+    ///
+    /// If not set, installations are looked up in the registry on Windows.
+    pub fn versions_dir(&self) -> Option<&PathBuf> {
+        self.versions_dir.as_ref()
+    }
+
+    pub fn set_versions_dir<P: Into<PathBuf>>(&mut self, path: P) {
+        self.versions_dir = Some(path.into());
+    }
+
+    /// Path to pythonup's `installation.json`.
+    ///
+    /// If set, commands to resolve use the first active version in pythonup's
+    /// configuration when no versions are pinned.
+    pub fn installation(&self) -> Option<&PathBuf> {
+        self.installation.as_ref()
+    }
+
+    pub fn set_installation<P: Into<PathBuf>>(&mut self, path: P) {
+        self.installation = Some(path.into());
+    }
+
+    pub fn report(&self) -> Report {
+        self.report
+    }
+
+    pub fn set_report(&mut self, report: Report) {
+        self.report = report;
+    }
+
+    /// Directory whose changes decide whether commands after the first run.
+    ///
+    /// If set, the directory's listing, with sizes and modification times,
+    /// is compared before and after the first command runs. Other commands
+    /// are skipped if nothing changed.
+    pub fn watch_dir(&self) -> Option<&PathBuf> {
+        self.watch_dir.as_ref()
+    }
+
+    pub fn set_watch_dir<P: Into<PathBuf>>(&mut self, path: P) {
+        self.watch_dir = Some(path.into());
+    }
+
+    pub fn on_change(&self) -> OnChange {
+        self.on_change
+    }
+
+    pub fn set_on_change(&mut self, on_change: OnChange) {
+        self.on_change = on_change;
+    }
+
+    /// How many shims may run each other before this one refuses to.
+    ///
+    /// Shims pass their nesting depth on to their children, so a shim
+    /// running itself, e.g. through `PATH`, does not do so endlessly.
+    pub fn max_depth(&self) -> u8 {
+        self.max_depth.unwrap_or(DEFAULT_MAX_DEPTH)
+    }
+
+    /// Whether the maximum depth is set, instead of the default.
+    pub fn has_max_depth(&self) -> bool {
+        self.max_depth.is_some()
+    }
+
+    pub fn set_max_depth(&mut self, depth: u8) {
+        self.max_depth = Some(depth);
+    }
+
+    /// Script to run with its shebang before other commands, in launcher
+    /// mode. See `shebang`.
+    pub fn script(&self) -> Option<&PathBuf> {
+        self.script.as_ref()
+    }
+
+    pub fn set_script<P: Into<PathBuf>>(&mut self, path: P) {
+        self.script = Some(path.into());
+    }
+
+    /// Shebang line to run the script with, instead of the script's own.
+    pub fn shebang(&self) -> Option<&OsString> {
+        self.shebang.as_ref()
+    }
+
+    pub fn set_shebang<S: Into<OsString>>(&mut self, line: S) {
+        self.shebang = Some(line.into());
+    }
+
+    /// Zip archive appended to the shim, run with the shim as the script.
+    ///
+    /// The shebang line before the archive, if any, is used if the shim has
+    /// none. See `zipapp`.
+    pub fn zipapp(&self) -> Option<&Zipapp> {
+        self.zipapp.as_ref()
+    }
+
+    pub fn set_zipapp(&mut self, zipapp: Zipapp) {
+        self.zipapp = Some(zipapp);
+    }
+}
+
+/// Name of a shim, without the `.exe` extension.
+///
+/// This is not `file_stem()`, since names of shims for a version contain a
+/// dot, e.g. `pip3.7`.
+pub fn shim_name(shim: &Path) -> Option<&str> {
+    let name = shim.file_name()?.to_str()?;
+    Some(match name.len().checked_sub(4) {
+        Some(i) if name[i..].eq_ignore_ascii_case(".exe") => &name[..i],
+        _ => name,
+    })
+}
+
+/// Values of placeholders in commands, expanded when the shim is launched.
+///
+/// Placeholders are written in braces, e.g. `{shim_dir}`. Environment
+/// variables are also expanded, written as `%VAR%` or `${VAR}`. Undefined
+/// variables are kept as-is in the former form (like cmd), and expanded to
+/// nothing in the latter (like sh). Doubling a special character (`{{`,
+/// `}}`, `%%`, `$$`) escapes it. Other text, including unknown placeholders,
+/// is kept as-is.
+///
+/// The shim's own arguments are placed with `{args}` and `{argN}` (counting
+/// from 1). An argument consisting of `{args}` alone is replaced by all of
+/// them, and one of `{argN}` alone is dropped if there are fewer than N.
+/// Elsewhere, `{args}` expands to the arguments joined by spaces, and a
+/// missing `{argN}` to nothing.
+#[derive(Debug, Default)]
+pub struct Placeholders {
+    /// Directory containing the shim, for `{shim_dir}`.
+    pub shim_dir: PathBuf,
+
+    /// Name of the shim without `.exe`, for `{shim_name}`.
+    pub shim_name: OsString,
+
+    /// pythonup's base directory, for `{base_dir}`. This is only known if
+    /// the shim has `Options::installation`, which may itself contain other
+    /// placeholders.
+    pub base_dir: Option<PathBuf>,
+
+    /// Arguments passed to the shim, for `{args}` and `{argN}`.
+    pub args: Vec<OsString>,
+
+    // Whether arguments are placed in the string last expanded.
+    args_placed: Cell<bool>,
+}
+
+impl Placeholders {
+    pub fn for_shim(shim: &Path, options: &Options) -> Self {
+        let shim_name = match shim_name(shim) {
+            Some(name) => OsString::from(name),
+            None => shim.file_name().unwrap_or_default().to_os_string(),
+        };
+        let mut placeholders = Placeholders {
+            shim_dir: shim.parent().map(Path::to_path_buf).unwrap_or_default(),
+            shim_name,
+            base_dir: None,
+            args: env::args_os().skip(1).collect(),
+            args_placed: Cell::new(false),
+        };
+        placeholders.base_dir = options.installation().and_then(|p| {
+            resolve::base_dir(Path::new(&placeholders.expand(p.as_os_str())))
+        });
+        placeholders
+    }
+
+    fn get(&self, name: &str) -> Option<OsString> {
+        if name == "args" {
+            self.args_placed.set(true);
+            let mut joined = OsString::new();
+            for (i, arg) in self.args.iter().enumerate() {
+                if i > 0 {
+                    joined.push(" ");
+                }
+                joined.push(arg);
+            }
+            return Some(joined);
+        }
+        if let Some(n) = arg_index(name) {
+            self.args_placed.set(true);
+            return Some(self.args.get(n - 1).cloned().unwrap_or_default());
+        }
+        match name {
+            "shim_dir" => Some(self.shim_dir.clone().into_os_string()),
+            "shim_name" => Some(self.shim_name.clone()),
+            "base_dir" => self.base_dir.clone().map(PathBuf::into_os_string),
+            _ => None,
+        }
+    }
+
+    // Expand an argument into any number of arguments.
+    fn expand_arg(&self, arg: &OsStr, expanded: &mut Vec<OsString>) {
+        let name = arg.to_str()
+            .and_then(|s| s.strip_prefix('{'))
+            .and_then(|s| s.strip_suffix('}'));
+        match name {
+            Some("args") => {
+                self.args_placed.set(true);
+                expanded.extend(self.args.iter().cloned());
+            },
+            Some(name) if arg_index(name).is_some() => {
+                self.args_placed.set(true);
+                let n = arg_index(name).unwrap();
+                expanded.extend(self.args.get(n - 1).cloned());
+            },
+            _ => { expanded.push(self.expand(arg)); },
+        }
+    }
+
+    /// Expand placeholders and environment variables in a string.
+    ///
+    /// Strings that are not valid Unicode are returned as-is.
+    pub fn expand(&self, s: &OsStr) -> OsString {
+        self.expand_with(s, |name| env::var_os(name))
+    }
+
+    fn expand_with<F>(&self, s: &OsStr, var: F) -> OsString
+            where F: Fn(&str) -> Option<OsString> {
+        let mut rest = match s.to_str() {
+            Some(s) => s,
+            None => { return s.to_os_string(); },
+        };
+        let mut expanded = OsString::new();
+        while let Some(i) = rest.find(['{', '}', '%', '$']) {
+            expanded.push(&rest[..i]);
+            let (value, len) = self.expand_token(&rest[i..], &var);
+            expanded.push(value);
+            rest = &rest[i + len..];
+        }
+        expanded.push(rest);
+        expanded
+    }
+
+    // Expand the token at the start of `s`, returning its value, and how
+    // many bytes it spans.
+    fn expand_token<F>(&self, s: &str, var: &F) -> (OsString, usize)
+            where F: Fn(&str) -> Option<OsString> {
+        let special = &s[..1];
+        if s[1..].starts_with(special) {
+            return (OsString::from(special), 2);
+        }
+        let expanded = match special {
+            "{" => enclosed(&s[1..], '}').and_then(|name| {
+                Some((self.get(name)?, name.len() + 2))
+            }),
+            "%" => enclosed(&s[1..], '%').filter(|name| {
+                is_var_name(name, "_()")
+            }).map(|name| {
+                // Undefined variables are left alone, including both ends.
+                let len = name.len() + 2;
+                (var(name).unwrap_or_else(|| OsString::from(&s[..len])), len)
+            }),
+            "$" if s[1..].starts_with('{') => enclosed(&s[2..], '}').filter(
+                |name| is_var_name(name, "_"),
+            ).map(|name| {
+                (var(name).unwrap_or_default(), name.len() + 3)
+            }),
+            _ => None,
+        };
+        expanded.unwrap_or_else(|| (OsString::from(special), 1))
+    }
+}
+
+// N in "argN", counting from 1.
+fn arg_index(name: &str) -> Option<usize> {
+    let digits = name.strip_prefix("arg")?;
+    if !digits.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    digits.parse().ok().filter(|&n| n > 0)
+}
+
+// Text in `s` before the closing character, if it is found.
+fn enclosed(s: &str, close: char) -> Option<&str> {
+    s.find(close).map(|i| &s[..i])
+}
+
+fn is_var_name(name: &str, extra: &str) -> bool {
+    !name.is_empty()
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || extra.contains(c))
+}
+
+impl Command {
+    /// A copy of the command with placeholders expanded.
+    ///
+    /// Placeholders are expanded in the executable, arguments, environment
+    /// values, and working directory. If the shim's own arguments are placed
+    /// in the command, they are not appended to it anymore.
+    pub fn expanded(&self, placeholders: &Placeholders) -> Self {
+        placeholders.args_placed.set(false);
+        let path = |p: &PathBuf| {
+            PathBuf::from(placeholders.expand(p.as_os_str()))
+        };
+        let env = self.env.iter().map(|op| match *op {
+            EnvOp::Set(ref name, ref value) => {
+                EnvOp::Set(name.clone(), placeholders.expand(value))
+            },
+            EnvOp::Unset(ref name) => EnvOp::Unset(name.clone()),
+            EnvOp::PrependPath(ref name, ref entry) => {
+                EnvOp::PrependPath(name.clone(), placeholders.expand(entry))
+            },
+        }).collect();
+        let mut args = vec![];
+        for arg in &self.args {
+            placeholders.expand_arg(arg, &mut args);
+        }
+        Command {
+            exe: self.exe.as_ref().map(path),
+            args,
+            env,
+            working_dir: self.working_dir.as_ref().map(path),
+            resolve: self.resolve.clone(),
+            series: self.series.clone(),
+            appends_own_args: self.appends_own_args
+                && !placeholders.args_placed.get(),
+            condition: self.condition,
+            detached: self.detached,
+            exe_digest: self.exe_digest,
+            prepends_installation: self.prepends_installation,
+        }
+    }
 }
 
 pub struct Commands {
-    iter: IntoIter<Result<u8, io::Error>>,
-    done: bool,
+    iter: IntoIter<Command>,
+    options: Options,
+    legacy: bool,
+}
+
+fn corrupt(message: &str) -> Error {
+    Error::Corrupt(String::from(message))
+}
+
+// The command records about a command apply to, i.e. the last one.
+fn current(commands: &mut [Command], tag: u8) -> Result<&mut Command, Error> {
+    commands.last_mut().ok_or_else(|| {
+        Error::Corrupt(format!("record {:#04x} outside command", tag))
+    })
+}
+
+fn env_op(tag: u8, strings: Vec<OsString>) -> Result<EnvOp, Error> {
+    let mut strings = strings.into_iter();
+    let op = match (tag, strings.next(), strings.next()) {
+        (tags::ENV_SET, Some(name), Some(value)) => EnvOp::Set(name, value),
+        (tags::ENV_UNSET, Some(name), None) => EnvOp::Unset(name),
+        (tags::ENV_PREPEND_PATH, Some(name), Some(entry)) => {
+            EnvOp::PrependPath(name, entry)
+        },
+        _ => { return Err(corrupt("bad environment record")); },
+    };
+    if strings.next().is_some() {
+        return Err(corrupt("bad environment record"));
+    }
+    Ok(op)
+}
+
+fn single_byte(data: &[u8]) -> Option<u8> {
+    match *data {
+        [byte] => Some(byte),
+        _ => None,
+    }
 }
 
 // Helper to push a byte array as argument into command.
@@ -41,75 +660,364 @@ macro_rules! push_arg {
     ( $command:expr, $bytes:expr ) => {
         match String::from_utf8($bytes) {
             Ok(arg) => { $command.arg(arg); },
-            Err(e) => {
-                eprintln!("bad shim: {}", e);
-                return None;
-            },
+            Err(e) => { return Err(Error::Corrupt(e.to_string())); },
         }
     };
 }
 
+// Read a command from legacy shim data. `None` is returned at the end of
+// the data.
+fn read_legacy_command(iter: &mut IntoIter<u8>)
+        -> Result<Option<Command>, Error> {
+    let mut command = Command::new();
+    let mut bytes = vec![];
+    for byte in iter { match byte {
+        10 => { // Line feed signifies end of command.
+            if !bytes.is_empty() {
+                push_arg!(command, bytes);
+            }
+            if command.exe().is_none() {
+                return Ok(None);
+            }
+            return Ok(Some(command));
+        },
+        0 => {  // Null signifies end of argument.
+            push_arg!(command, bytes);
+            bytes = vec![];
+        },
+        _ => { bytes.push(byte); },
+    }}
+    Ok(None)
+}
+
 impl Commands {
-    pub fn from(bytes: Vec<Result<u8, io::Error>>) -> Self {
-        Commands { iter: bytes.into_iter(), done: false }
+    pub fn new(commands: Vec<Command>, options: Options) -> Self {
+        Commands { iter: commands.into_iter(), options, legacy: false }
     }
 
-    pub fn from_current_exe() -> Result<Self, String> {
-        let exe = current_exe().map_err(|e| e.to_string())?;
-        let file = File::open(&exe).map_err(|e| e.to_string())?;
-
-        let mut bytes: Vec<_> = io::BufReader::new(file).bytes().collect();
-        bytes.reverse();
-
-        Ok(Self::from(bytes))
+    /// Parse commands from legacy shim data, in reading order.
+    pub fn from(bytes: Vec<u8>) -> Result<Self, Error> {
+        let mut iter = bytes.into_iter();
+        let mut commands = vec![];
+        while let Some(command) = read_legacy_command(&mut iter)? {
+            commands.push(command);
+        }
+        Ok(Commands {
+            iter: commands.into_iter(),
+            options: Options::default(),
+            legacy: true,
+        })
     }
 
-    fn read_next_command(&mut self) -> Option<Command> {
-        let mut command = Command::new();
-        let mut bytes = vec![];
-        while let Some(result) = self.iter.next() { match result {
-            Ok(byte) => match byte {
-                10 => { // Line feed signifies end of command.
-                    if !bytes.is_empty() {
-                        push_arg!(command, bytes);
+    /// Build commands from records in a versioned trailer.
+    pub fn from_records(records: Vec<Record>) -> Result<Self, Error> {
+        let mut commands: Vec<Command> = vec![];
+        let mut options = Options::default();
+        for record in records {
+            match record.tag {
+                tags::COMMAND => { commands.push(Command::new()); },
+                tags::ARG => {
+                    let arg = String::from_utf8(record.data).map_err(|e| {
+                        Error::Corrupt(e.to_string())
+                    })?;
+                    current(&mut commands, record.tag)?.arg(arg);
+                },
+                tags::OS_ARG => {
+                    let arg = trailer::decode_os_str(&record.data)?;
+                    current(&mut commands, record.tag)?.arg(arg);
+                },
+                tags::ENV_SET | tags::ENV_UNSET | tags::ENV_PREPEND_PATH => {
+                    let strings = trailer::decode_os_strs(&record.data)?;
+                    let op = env_op(record.tag, strings)?;
+                    current(&mut commands, record.tag)?.push_env(op);
+                },
+                tags::WORKING_DIR => {
+                    let path = trailer::decode_os_str(&record.data)?;
+                    current(&mut commands, record.tag)?.set_working_dir(path);
+                },
+                tags::NO_OWN_ARGS => {
+                    current(&mut commands, record.tag)?
+                        .set_appends_own_args(false);
+                },
+                tags::CONDITION => {
+                    let condition = single_byte(&record.data)
+                        .and_then(Condition::from_byte)
+                        .ok_or_else(|| corrupt("bad condition"))?;
+                    current(&mut commands, record.tag)?
+                        .set_condition(condition);
+                },
+                tags::DETACHED => {
+                    current(&mut commands, record.tag)?.set_detached(true);
+                },
+                tags::PREPEND_INSTALLATION => {
+                    current(&mut commands, record.tag)?
+                        .set_prepends_installation(true);
+                },
+                tags::EXE_DIGEST => {
+                    if record.data.len() != DIGEST_SIZE {
+                        return Err(corrupt("bad digest"));
                     }
-                    return match command.exe() {
-                        Some(_) => Some(command),
-                        None => None,
-                    };
+                    let mut digest = [0; DIGEST_SIZE];
+                    digest.copy_from_slice(&record.data);
+                    current(&mut commands, record.tag)?.set_exe_digest(digest);
+                },
+                tags::ON_CHANGE => {
+                    let on_change = single_byte(&record.data)
+                        .and_then(OnChange::from_byte)
+                        .ok_or_else(|| corrupt("bad on-change policy"))?;
+                    options.set_on_change(on_change);
+                },
+                tags::SCRIPT => {
+                    options.set_script(trailer::decode_os_str(&record.data)?);
+                },
+                tags::SHEBANG => {
+                    let line = trailer::decode_os_str(&record.data)?;
+                    options.set_shebang(line);
+                },
+                tags::ZIPAPP => {
+                    let zipapp = Zipapp::from_bytes(&record.data)
+                        .ok_or_else(|| corrupt("bad zip archive size"))?;
+                    options.set_zipapp(zipapp);
+                },
+                tags::MAX_DEPTH => {
+                    let depth = single_byte(&record.data)
+                        .ok_or_else(|| corrupt("bad maximum depth"))?;
+                    options.set_max_depth(depth);
+                },
+                tags::WATCH_DIR => {
+                    let dir = trailer::decode_os_str(&record.data)?;
+                    options.set_watch_dir(dir);
+                },
+                tags::REPORT => {
+                    let report = single_byte(&record.data)
+                        .and_then(Report::from_byte)
+                        .ok_or_else(|| corrupt("bad report"))?;
+                    options.set_report(report);
+                },
+                tags::RESOLVE => {
+                    let path = trailer::decode_os_str(&record.data)?;
+                    current(&mut commands, record.tag)?.set_resolve(path);
+                },
+                tags::VERSIONS_DIR => {
+                    options.set_versions_dir(
+                        trailer::decode_os_str(&record.data)?,
+                    );
+                },
+                tags::INSTALLATION => {
+                    options.set_installation(
+                        trailer::decode_os_str(&record.data)?,
+                    );
                 },
-                0 => {  // Null signifies end of argument.
-                    push_arg!(command, bytes);
-                    bytes = vec![];
+                tags::MAC => {
+                    return Err(corrupt("signature before end of data"));
                 },
-                _ => { bytes.push(byte); },
+                tags::SERIES => {
+                    let series = String::from_utf8(record.data).map_err(|e| {
+                        Error::Corrupt(e.to_string())
+                    })?;
+                    current(&mut commands, record.tag)?.set_series(series);
+                },
+                tag if tags::is_optional(tag) => {},
+                tag => {
+                    return Err(Error::Corrupt(
+                        format!("bad record {:#04x}", tag),
+                    ));
+                },
+            }
+        }
+        if commands.iter().any(|c| c.exe().is_none()) {
+            return Err(corrupt("command without executable"));
+        }
+        Ok(Commands { iter: commands.into_iter(), options, legacy: false })
+    }
+
+    pub fn from_reader<R: Read + Seek>(reader: &mut R)
+            -> Result<Self, Error> {
+        Self::from_signed_reader(reader, None)
+    }
+
+    /// Read commands, which must be signed with the key if one is given.
+    ///
+    /// Legacy shim data cannot be signed, so are refused with a key.
+    pub fn from_signed_reader<R: Read + Seek>(
+            reader: &mut R, key: Option<&[u8]>) -> Result<Self, Error> {
+        // Prefer the versioned format, possibly before a zip archive, and
+        // fall back to legacy data.
+        let (payload, location) = match trailer::read(reader)? {
+            Some(payload) => (Some(payload), None),
+            None => match zipapp::locate(reader)? {
+                Some(location) => (
+                    trailer::read_before(reader, location.data_end)?,
+                    Some(location),
+                ),
+                None => (None, None),
+            },
+        };
+        match payload {
+            Some(payload) => {
+                let records = auth::authenticate(&payload, key)?;
+                Self::from_records(trailer::parse(records)?)?
+                    .with_zipapp(reader, location, key.is_some())
             },
-            Err(e) => {
-                eprintln!("shim read failure: {}", e);
-                return None;
+            None if key.is_some() => Err(Error::Unauthenticated(
+                String::from("legacy shims cannot be signed"),
+            )),
+            None => Self::from(read_legacy_trailer(reader)?),
+        }
+    }
+
+    // Check the appended archive against the data, and take its shebang.
+    fn with_zipapp<R: Read + Seek>(
+            mut self, reader: &mut R, location: Option<Location>,
+            authenticated: bool) -> Result<Self, Error> {
+        let (zipapp, location) = match (self.options.zipapp, location) {
+            (None, None) => { return Ok(self); },
+            (Some(zipapp), Some(location)) => (zipapp, location),
+            (Some(_), None) => { return Err(corrupt("missing zip archive")); },
+            (None, Some(_)) => {
+                return Err(corrupt("zip archive after shim data"));
             },
-        }}
-        None
+        };
+        // The digest is only worth reading the archive for if signed.
+        if authenticated {
+            if zipapp::appended(reader, location.data_end)? != zipapp {
+                return Err(Error::Unauthenticated(
+                    String::from("zip archive does not match signature"),
+                ));
+            }
+        } else if reader.seek(SeekFrom::End(0))? - location.data_end
+                != zipapp.size {
+            return Err(corrupt("zip archive size mismatch"));
+        }
+        if self.options.shebang.is_none() {
+            self.options.shebang = location.shebang;
+        }
+        Ok(self)
+    }
+
+    pub fn from_path(path: &Path) -> Result<Self, Error> {
+        let mut file = File::open(path).map_err(Error::Read)?;
+        Self::from_reader(&mut file)
+    }
+
+    /// Read commands of the running shim, with placeholders expanded.
+    ///
+    /// If the user has a key, the shim must be signed with it. Shims with a
+    /// zip archive run themselves as their script.
+    pub fn from_current_exe() -> Result<Self, Error> {
+        let exe = current_exe().map_err(Error::Read)?;
+        let key = auth::user_key()?;
+        let mut file = File::open(&exe).map_err(Error::Read)?;
+        let commands = Self::from_signed_reader(
+            &mut file, key.as_ref().map(|key| &key[..]),
+        )?;
+        let placeholders = Placeholders::for_shim(&exe, commands.options());
+        let mut commands = commands.expanded(&placeholders);
+        if commands.options.zipapp.is_some() {
+            commands.options.script = Some(exe);
+        }
+        commands.with_script()
+    }
+
+    /// Put the command running the shim's script first, in launcher mode.
+    pub fn with_script(self) -> Result<Self, Error> {
+        let script = match self.options.script() {
+            Some(script) => script.clone(),
+            None => { return Ok(self); },
+        };
+        let mut commands = vec![
+            shebang::script_command(&script, &self.options)?,
+        ];
+        commands.extend(self.iter);
+        Ok(Commands { iter: commands.into_iter(), ..self })
+    }
+
+    pub fn options(&self) -> &Options {
+        &self.options
+    }
+
+    /// Commands not iterated through yet.
+    pub fn as_slice(&self) -> &[Command] {
+        self.iter.as_slice()
     }
+
+    /// Whether the commands are read from legacy shim data.
+    pub fn is_legacy(&self) -> bool {
+        self.legacy
+    }
+
+    /// Expand placeholders in the remaining commands.
+    ///
+    /// Legacy shims do not support placeholders, and are returned as-is, so
+    /// braces and percent signs in their arguments are kept.
+    pub fn expanded(self, placeholders: &Placeholders) -> Self {
+        if self.legacy {
+            return self;
+        }
+        let commands: Vec<_> = self.iter
+            .map(|command| command.expanded(placeholders))
+            .collect();
+        let path = |p: PathBuf| {
+            PathBuf::from(placeholders.expand(p.as_os_str()))
+        };
+        let mut options = self.options;
+        options.versions_dir = options.versions_dir.map(path);
+        options.installation = options.installation.map(path);
+        options.watch_dir = options.watch_dir.map(path);
+        options.script = options.script.map(path);
+        Commands { iter: commands.into_iter(), options, ..self }
+    }
+}
+
+/// Read legacy shim data from the end of a file.
+///
+/// The data are stored backwards, so the file is read backwards in chunks
+/// from its end, until the two line feeds terminating the command sequence
+/// are found. The returned bytes are in reading order (i.e. reversed from
+/// the file), including the terminator.
+pub fn read_legacy_trailer<R: Read + Seek>(reader: &mut R)
+        -> io::Result<Vec<u8>> {
+    let mut pos = reader.seek(SeekFrom::End(0))?;
+    let mut bytes = vec![];
+    let mut chunk = [0u8; CHUNK_SIZE as usize];
+    while pos > 0 {
+        let size = CHUNK_SIZE.min(pos);
+        pos -= size;
+        reader.seek(SeekFrom::Start(pos))?;
+
+        let chunk = &mut chunk[..size as usize];
+        reader.read_exact(chunk)?;
+
+        // The terminator may straddle the previous chunk's boundary.
+        let start = bytes.len().saturating_sub(1);
+        bytes.extend(chunk.iter().rev());
+        let found = bytes[start..].windows(2).position(|w| w == b"\n\n");
+        if let Some(i) = found {
+            bytes.truncate(start + i + 2);
+            return Ok(bytes);
+        }
+    }
+    Err(io::Error::new(io::ErrorKind::InvalidData, "shim data not found"))
 }
 
 impl Iterator for Commands {
     type Item = Command;
 
     fn next(&mut self) -> Option<Self::Item> {
-        if self.done {
-            return None;
-        }
-        match self.read_next_command() {
-            r @ Some(_) => r,
-            None => { self.done = true; None },
-        }
+        self.iter.next()
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.iter.size_hint()
     }
 }
 
+impl ExactSizeIterator for Commands {}
+
 
 #[cfg(test)]
 mod command_test {
+    use std::ffi::OsString;
     use std::path::PathBuf;
     use super::Command;
 
@@ -117,36 +1025,312 @@ mod command_test {
     fn new() {
         let command = Command::new();
         assert_eq!(command.exe(), None);
-        assert_eq!(command.args(), &Vec::<String>::new());
+        assert_eq!(command.args(), &Vec::<OsString>::new());
     }
 
     #[test]
     fn push_exe() {
         let mut command = Command::new();
-        command.arg(String::from("C:\\python.exe"));
+        command.arg(OsString::from("C:\\python.exe"));
         assert_eq!(command.exe(), Some(&PathBuf::from("C:\\python.exe")));
-        assert_eq!(command.args(), &Vec::<String>::new());
+        assert_eq!(command.args(), &Vec::<OsString>::new());
     }
 
     #[test]
     fn push_exe_arg() {
         let mut command = Command::new();
-        command.arg(String::from("C:\\python.exe"));
-        command.arg(String::from("-OO"));
-        command.arg(String::from("-c"));
-        command.arg(String::from("import sys; print(sys.executable)"));
+        command.arg(OsString::from("C:\\python.exe"));
+        command.arg(OsString::from("-OO"));
+        command.arg(OsString::from("-c"));
+        command.arg(OsString::from("import sys; print(sys.executable)"));
         assert_eq!(command.exe(), Some(&PathBuf::from("C:\\python.exe")));
         assert_eq!(command.args(), &vec![
-            String::from("-OO"),
-            String::from("-c"),
-            String::from("import sys; print(sys.executable)"),
+            OsString::from("-OO"),
+            OsString::from("-c"),
+            OsString::from("import sys; print(sys.executable)"),
         ]);
     }
 }
 
 
+#[cfg(test)]
+mod sequencing_test {
+    use super::{Condition, Report};
+
+    #[test]
+    fn conditions() {
+        assert!(Condition::Always.allows(0));
+        assert!(Condition::Always.allows(1));
+        assert!(Condition::OnSuccess.allows(0));
+        assert!(!Condition::OnSuccess.allows(-1));
+        assert!(!Condition::OnFailure.allows(0));
+        assert!(Condition::OnFailure.allows(2));
+        assert_eq!(Condition::from_byte(2), Some(Condition::OnFailure));
+        assert_eq!(Condition::from_byte(3), None);
+    }
+
+    #[test]
+    fn reports() {
+        let codes = [0, 3, -1_073_741_819, 1];
+        assert_eq!(Report::First.choose(&codes), 0);
+        assert_eq!(Report::Last.choose(&codes), 1);
+        assert_eq!(Report::Worst.choose(&codes), -1_073_741_819);
+        assert_eq!(Report::Worst.choose(&[0, 0]), 0);
+        assert_eq!(Report::Last.choose(&[]), 0);
+        assert_eq!(Report::from_byte(0), Some(Report::First));
+    }
+}
+
+
+#[cfg(test)]
+mod placeholders_test {
+    use std::ffi::{OsStr, OsString};
+    use std::path::{Path, PathBuf};
+    use trailer::{tags, Record};
+    use testing::TempDir;
+    use super::{shim_name, Command, Commands, EnvOp, Options, Placeholders};
+
+    fn placeholders() -> Placeholders {
+        Placeholders {
+            shim_dir: PathBuf::from("/opt/py/cmd"),
+            shim_name: OsString::from("pip3.7"),
+            base_dir: Some(PathBuf::from("/opt/py")),
+            args: vec![OsString::from("a b"), OsString::from("-c")],
+            ..Placeholders::default()
+        }
+    }
+
+    fn expand(s: &str) -> OsString {
+        placeholders().expand_with(OsStr::new(s), |name| match name {
+            "HOME" => Some(OsString::from("/home/me")),
+            "ProgramFiles(x86)" => Some(OsString::from("C:\\PF")),
+            "EMPTY" => Some(OsString::new()),
+            _ => None,
+        })
+    }
+
+    #[test]
+    fn placeholders_expanded() {
+        assert_eq!(expand("{shim_dir}/python"), "/opt/py/cmd/python");
+        assert_eq!(expand("{base_dir}{shim_name}"), "/opt/pypip3.7");
+        assert_eq!(expand("-m{shim_name}-{shim_name}"), "-mpip3.7-pip3.7");
+    }
+
+    #[test]
+    fn unknown_placeholders() {
+        assert_eq!(expand("{nope}"), "{nope}");
+        assert_eq!(expand("print({})"), "print({})");
+        assert_eq!(expand("{\"a\": 1}"), "{\"a\": 1}");
+        assert_eq!(expand("{shim_dir"), "{shim_dir");
+        assert_eq!(expand("shim_dir}"), "shim_dir}");
+
+        let mut p = placeholders();
+        p.base_dir = None;
+        assert_eq!(p.expand(OsStr::new("{base_dir}")), "{base_dir}");
+    }
+
+    #[test]
+    fn percent_vars() {
+        assert_eq!(expand("%HOME%/.pip"), "/home/me/.pip");
+        assert_eq!(expand("%ProgramFiles(x86)%\\x"), "C:\\PF\\x");
+        assert_eq!(expand("%HOME%%HOME%"), "/home/me/home/me");
+        assert_eq!(expand("a%EMPTY%b"), "ab");
+
+        // Undefined variables, and things that are not variables.
+        assert_eq!(expand("%UNDEFINED%"), "%UNDEFINED%");
+        assert_eq!(expand("%UNDEFINED%HOME%"), "%UNDEFINED%HOME%");
+        assert_eq!(expand("50% of 20%"), "50% of 20%");
+        assert_eq!(expand("100%"), "100%");
+        assert_eq!(expand("%%"), "%");
+    }
+
+    #[test]
+    fn dollar_vars() {
+        assert_eq!(expand("${HOME}/.pip"), "/home/me/.pip");
+        assert_eq!(expand("a${UNDEFINED}b"), "ab");
+        assert_eq!(expand("$HOME"), "$HOME");
+        assert_eq!(expand("${}"), "${}");
+        assert_eq!(expand("${HOME"), "${HOME");
+        assert_eq!(expand("${A B}"), "${A B}");
+        assert_eq!(expand("$"), "$");
+    }
+
+    #[test]
+    fn escapes() {
+        assert_eq!(expand("{{shim_dir}}"), "{shim_dir}");
+        assert_eq!(expand("{{{shim_name}}}"), "{pip3.7}");
+        assert_eq!(expand("%%HOME%%"), "%HOME%");
+        assert_eq!(expand("$${HOME}"), "${HOME}");
+        assert_eq!(expand("$$$$"), "$$");
+        assert_eq!(expand("}}{{"), "}{");
+    }
+
+    #[test]
+    fn unicode() {
+        assert_eq!(
+            expand("caf\u{e9}/{shim_name}/\u{1f40d}"),
+            "caf\u{e9}/pip3.7/\u{1f40d}",
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn non_unicode() {
+        use std::os::unix::ffi::OsStringExt;
+        let s = OsString::from_vec(b"{shim_dir}\xff".to_vec());
+        assert_eq!(placeholders().expand(&s), s);
+    }
+
+    #[test]
+    fn command() {
+        let mut command = Command::new();
+        command.arg("{shim_dir}/../python.exe");
+        command.arg("{{shim_name}}");
+        command.arg("{shim_name}");
+        command.push_env(EnvOp::Set("A".into(), "{base_dir}".into()));
+        command.push_env(EnvOp::Unset("{shim_name}".into()));
+        command.push_env(EnvOp::PrependPath("P".into(), "{base_dir}".into()));
+        command.set_working_dir("{base_dir}/lib");
+        command.set_resolve("{shim_name}");
+
+        let mut expected = Command::new();
+        expected.arg("/opt/py/cmd/../python.exe");
+        expected.arg("{shim_name}");
+        expected.arg("pip3.7");
+        expected.push_env(EnvOp::Set("A".into(), "/opt/py".into()));
+        expected.push_env(EnvOp::Unset("{shim_name}".into()));
+        expected.push_env(EnvOp::PrependPath("P".into(), "/opt/py".into()));
+        expected.set_working_dir("/opt/py/lib");
+        expected.set_resolve("{shim_name}");
+        assert_eq!(command.expanded(&placeholders()), expected);
+    }
+
+    fn expand_args(args: &[&str]) -> Command {
+        let mut command = Command::new();
+        command.arg("python");
+        for arg in args {
+            command.arg(*arg);
+        }
+        command.expanded(&placeholders())
+    }
+
+    fn os_strings(args: &[&str]) -> Vec<OsString> {
+        args.iter().map(OsString::from).collect()
+    }
+
+    #[test]
+    fn own_args() {
+        let command = expand_args(&["-m", "tool", "{args}", "--config", "X"]);
+        assert_eq!(
+            command.args(),
+            &os_strings(&["-m", "tool", "a b", "-c", "--config", "X"]),
+        );
+        assert!(!command.appends_own_args());
+
+        let command = expand_args(&["{arg2}", "{arg1}", "{arg3}", "{arg1}"]);
+        assert_eq!(command.args(), &os_strings(&["-c", "a b", "a b"]));
+        assert!(!command.appends_own_args());
+    }
+
+    #[test]
+    fn own_args_embedded() {
+        let command = expand_args(&["--x={arg1}", "[{args}]", "--y={arg9}"]);
+        assert_eq!(
+            command.args(),
+            &os_strings(&["--x=a b", "[a b -c]", "--y="]),
+        );
+        assert!(!command.appends_own_args());
+    }
+
+    #[test]
+    fn own_args_not_placed() {
+        let command = expand_args(&["{{args}}", "{arg0}", "{argx}", "{arg}"]);
+        assert_eq!(
+            command.args(),
+            &os_strings(&["{args}", "{arg0}", "{argx}", "{arg}"]),
+        );
+        assert!(command.appends_own_args());
+
+        let mut p = placeholders();
+        p.args.clear();
+        let mut command = Command::new();
+        command.arg("python");
+        command.arg("{args}");
+        let command = command.expanded(&p);
+        assert!(command.args().is_empty());
+        assert!(!command.appends_own_args());
+    }
+
+    #[test]
+    fn legacy_not_expanded() {
+        let data = b"{shim_dir}\0%HOME%\0\n".to_vec();
+        let commands = Commands::from(data).unwrap()
+            .expanded(&placeholders());
+        let command = commands.collect::<Vec<_>>().pop().unwrap();
+        assert_eq!(command.exe().unwrap(), Path::new("{shim_dir}"));
+        assert_eq!(command.args(), &vec![OsString::from("%HOME%")]);
+
+        let records = vec![
+            Record { tag: tags::COMMAND, data: vec![] },
+            Record { tag: tags::ARG, data: b"{shim_dir}".to_vec() },
+            Record { tag: tags::WATCH_DIR, data: b"\0{base_dir}/x".to_vec() },
+        ];
+        let commands = Commands::from_records(records).unwrap()
+            .expanded(&placeholders());
+        let watch_dir = commands.options().watch_dir().cloned();
+        assert_eq!(watch_dir, Some(PathBuf::from("/opt/py/x")));
+        let command = commands.collect::<Vec<_>>().pop().unwrap();
+        assert_eq!(command.exe().unwrap(), Path::new("/opt/py/cmd"));
+    }
+
+    #[test]
+    fn for_shim() {
+        let p = Placeholders::for_shim(
+            Path::new("/opt/py/cmd/pip3.7.EXE"), &Options::default(),
+        );
+        assert_eq!(p.shim_dir, Path::new("/opt/py/cmd"));
+        assert_eq!(p.shim_name, "pip3.7");
+        assert_eq!(p.base_dir, None);
+    }
+
+    #[test]
+    fn relative_installation() {
+        let dir = TempDir::new("cmds-relative-installation");
+        dir.write("lib/installation.json", r#"{"base_dir": ".."}"#);
+        let shim = dir.write("cmd/pip.exe", "MZ");
+        let mut options = Options::default();
+        options.set_installation("{shim_dir}/../lib/installation.json");
+        options.set_versions_dir("{base_dir}/versions");
+
+        let p = Placeholders::for_shim(&shim, &options);
+        let base_dir = dir.join("cmd/../lib/..");
+        assert_eq!(p.base_dir, Some(base_dir.clone()));
+
+        let commands = Commands::new(vec![], options).expanded(&p);
+        let options = commands.options();
+        assert_eq!(
+            options.installation(),
+            Some(&dir.join("cmd/../lib/installation.json")),
+        );
+        assert_eq!(options.versions_dir(), Some(&base_dir.join("versions")));
+    }
+
+    #[test]
+    fn shim_names() {
+        fn name(s: &str) -> Option<&str> {
+            shim_name(Path::new(s))
+        }
+        assert_eq!(name("python3.7.exe"), Some("python3.7"));
+        assert_eq!(name("/cmd/pip3.7"), Some("pip3.7"));
+        assert_eq!(name("PIP.EXE"), Some("PIP"));
+        assert_eq!(name(".exe"), Some(""));
+    }
+}
+
+
 #[cfg(test)]
 mod commands_test {
+    use std::ffi::OsString;
     use std::path::PathBuf;
     use super::Commands;
 
@@ -156,39 +1340,192 @@ mod commands_test {
             C:\\python.exe\n\
             C:\\python.exe\0--version\n\
             C:\\python.exe\0-OO\0-c\0import sys; print(sys.executable)\n\
-        ".bytes().map(|b| Ok(b)).collect());
+        ".bytes().collect()).unwrap();
+        assert_eq!(commands.len(), 3);
 
         let command = commands.next().unwrap();
         assert_eq!(command.exe(), Some(&PathBuf::from("C:\\python.exe")));
-        assert_eq!(command.args(), &Vec::<String>::new());
+        assert_eq!(command.args(), &Vec::<OsString>::new());
 
         let command = commands.next().unwrap();
         assert_eq!(command.exe(), Some(&PathBuf::from("C:\\python.exe")));
-        assert_eq!(command.args(), &vec![String::from("--version")]);
+        assert_eq!(command.args(), &vec![OsString::from("--version")]);
 
         let command = commands.next().unwrap();
         assert_eq!(command.exe(), Some(&PathBuf::from("C:\\python.exe")));
         assert_eq!(command.args(), &vec![
-            String::from("-OO"),
-            String::from("-c"),
-            String::from("import sys; print(sys.executable)"),
+            OsString::from("-OO"),
+            OsString::from("-c"),
+            OsString::from("import sys; print(sys.executable)"),
         ]);
 
-        assert_eq!(commands.next().is_none(), true);
+        assert!(commands.next().is_none());
     }
 
     #[test]
     fn avoid_garbage() {
         let mut commands = Commands::from(
-            "C:\\python.exe\n\n12345".bytes().map(|b| Ok(b)).collect(),
-        );
+            "C:\\python.exe\n\n12345".bytes().collect(),
+        ).unwrap();
+
+        let command = commands.next().unwrap();
+        assert_eq!(command.exe(), Some(&PathBuf::from("C:\\python.exe")));
+        assert_eq!(command.args(), &Vec::<OsString>::new());
+
+        assert!(commands.next().is_none());
+        assert!(commands.next().is_none());
+        assert!(commands.next().is_none());
+    }
+
+    #[test]
+    fn bad_unicode() {
+        let e = Commands::from(b"C:\\python.exe\0\xff\n\n".to_vec())
+            .err().unwrap();
+        assert_eq!(e.exit_code(), 125);
+    }
+}
+
+
+#[cfg(test)]
+mod read_legacy_trailer_test {
+    use std::io::Cursor;
+    use super::{CHUNK_SIZE, read_legacy_trailer};
+
+    fn shim(exe: &[u8], data: &str) -> Cursor<Vec<u8>> {
+        let mut bytes = exe.to_vec();
+        bytes.extend(data.bytes().rev());
+        Cursor::new(bytes)
+    }
+
+    #[test]
+    fn stop_at_terminator() {
+        // The executable itself may contain line feeds.
+        let exe = [0x4d, 0x5a, 0x0a, 0x0a, 0x90];
+        let bytes = read_legacy_trailer(&mut shim(&exe, "C:\\python.exe\n\n"));
+        let bytes = bytes.unwrap();
+        assert_eq!(bytes, b"C:\\python.exe\n\n".to_vec());
+    }
+
+    #[test]
+    fn span_chunks() {
+        let exe = vec![0x90; CHUNK_SIZE as usize * 3];
+        let arg = "x".repeat(CHUNK_SIZE as usize * 2 + 7);
+        let data = format!("C:\\python.exe\0{}\n\n", arg);
+        let bytes = read_legacy_trailer(&mut shim(&exe, &data)).unwrap();
+        assert_eq!(bytes, data.into_bytes());
+    }
+
+    #[test]
+    fn terminator_on_chunk_boundary() {
+        // Place the two line feeds on each side of the first chunk boundary.
+        let arg = "x".repeat(CHUNK_SIZE as usize - 1);
+        let data = format!("{}\n\n", arg);
+        let bytes = read_legacy_trailer(&mut shim(&[0x90; 10], &data));
+        assert_eq!(bytes.unwrap(), data.into_bytes());
+    }
+
+    #[test]
+    fn not_found() {
+        let mut file = Cursor::new(vec![0x90; CHUNK_SIZE as usize + 3]);
+        assert!(read_legacy_trailer(&mut file).is_err());
+    }
+}
+
+
+#[cfg(test)]
+mod from_reader_test {
+    use std::ffi::OsString;
+    use std::io::Cursor;
+    use std::path::PathBuf;
+    use trailer::{checksum, tags, MAGIC, VERSION};
+    use super::Commands;
+
+    fn record(tag: u8, data: &str) -> Vec<u8> {
+        let mut bytes = vec![tag];
+        bytes.extend(&(data.len() as u32).to_le_bytes());
+        bytes.extend(data.bytes());
+        bytes
+    }
+
+    fn shim(records: &[Vec<u8>]) -> Cursor<Vec<u8>> {
+        let payload = records.concat();
+        let mut bytes = b"MZ\x90\x00".to_vec();
+        bytes.extend(&payload);
+        bytes.extend(&(payload.len() as u32).to_le_bytes());
+        bytes.extend(&checksum(&payload).to_le_bytes());
+        bytes.extend(&VERSION.to_le_bytes());
+        bytes.extend(MAGIC);
+        Cursor::new(bytes)
+    }
+
+    #[test]
+    fn versioned() {
+        let mut commands = Commands::from_reader(&mut shim(&[
+            record(tags::COMMAND, ""),
+            record(tags::ARG, "C:\\python.exe"),
+            record(tags::COMMAND, ""),
+            record(tags::ARG, "C:\\python.exe"),
+            record(tags::ARG, "-m"),
+            record(tags::ARG, "pythonup"),
+        ])).unwrap();
+
+        let command = commands.next().unwrap();
+        assert_eq!(command.exe(), Some(&PathBuf::from("C:\\python.exe")));
+        assert_eq!(command.args(), &Vec::<OsString>::new());
+
+        let command = commands.next().unwrap();
+        assert_eq!(command.exe(), Some(&PathBuf::from("C:\\python.exe")));
+        assert_eq!(command.args(), &vec![
+            OsString::from("-m"),
+            OsString::from("pythonup"),
+        ]);
+
+        assert!(commands.next().is_none());
+    }
+
+    #[test]
+    fn legacy_fallback() {
+        let mut bytes = b"MZ\x90\x00".to_vec();
+        bytes.extend(b"C:\\python.exe\0-E\n\n".iter().rev());
+        let mut commands = Commands::from_reader(&mut Cursor::new(bytes));
+        let commands = commands.as_mut().unwrap();
 
         let command = commands.next().unwrap();
         assert_eq!(command.exe(), Some(&PathBuf::from("C:\\python.exe")));
-        assert_eq!(command.args(), &Vec::<String>::new());
+        assert_eq!(command.args(), &vec![OsString::from("-E")]);
+
+        assert!(commands.next().is_none());
+    }
 
-        assert_eq!(commands.next().is_none(), true);
-        assert_eq!(commands.next().is_none(), true);
-        assert_eq!(commands.next().is_none(), true);
+    #[test]
+    fn argument_outside_command() {
+        let mut file = shim(&[record(tags::ARG, "C:\\python.exe")]);
+        assert!(Commands::from_reader(&mut file).is_err());
+    }
+
+    #[test]
+    fn command_without_exe() {
+        let mut file = shim(&[record(tags::COMMAND, "")]);
+        assert!(Commands::from_reader(&mut file).is_err());
+    }
+
+    #[test]
+    fn unknown_record() {
+        let mut file = shim(&[record(tags::COMMAND, ""), record(0x7f, "")]);
+        assert!(Commands::from_reader(&mut file).is_err());
+    }
+
+    #[test]
+    fn optional_record() {
+        let mut file = shim(&[
+            record(tags::COMMAND, ""),
+            record(tags::OPTIONAL, "future"),
+            record(tags::ARG, "python"),
+            record(0xff, ""),
+        ]);
+        let mut commands = Commands::from_reader(&mut file).unwrap();
+        let command = commands.next().unwrap();
+        assert_eq!(command.exe(), Some(&PathBuf::from("python")));
+        assert!(commands.next().is_none());
     }
 }
@@ -0,0 +1,127 @@
+//! SHA-256, to fingerprint executables run by shims, and HMAC-SHA256, to
+//! sign shim data, from the `sha2` and `hmac` crates.
+
+extern crate hmac;
+extern crate sha2;
+
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+
+use self::hmac::{Hmac, Mac};
+use self::sha2::Digest;
+
+pub const DIGEST_SIZE: usize = 32;
+
+/// Incremental SHA-256 hasher.
+#[derive(Clone, Default)]
+pub struct Sha256(sha2::Sha256);
+
+impl Sha256 {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        self.0.update(data);
+    }
+
+    pub fn finish(self) -> [u8; DIGEST_SIZE] {
+        self.0.finalize().into()
+    }
+}
+
+/// SHA-256 of the bytes.
+pub fn sha256(data: &[u8]) -> [u8; DIGEST_SIZE] {
+    sha2::Sha256::digest(data).into()
+}
+
+/// HMAC-SHA256 of the bytes, keyed with `key`.
+pub fn hmac_sha256(key: &[u8], data: &[u8]) -> [u8; DIGEST_SIZE] {
+    // HMAC takes keys of any length.
+    let mut mac = Hmac::<sha2::Sha256>::new_from_slice(key)
+        .expect("HMAC key of any length");
+    mac.update(data);
+    mac.finalize().into_bytes().into()
+}
+
+/// SHA-256 of a file's content.
+pub fn sha256_file(path: &Path) -> io::Result<[u8; DIGEST_SIZE]> {
+    let mut file = File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buf = [0u8; 8192];
+    loop {
+        match file.read(&mut buf)? {
+            0 => { break; },
+            n => hasher.update(&buf[..n]),
+        }
+    }
+    Ok(hasher.finish())
+}
+
+/// Format a digest in lowercase hex.
+pub fn hex(digest: &[u8]) -> String {
+    digest.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+
+#[cfg(test)]
+mod digest_test {
+    use super::{hex, hmac_sha256, sha256, Sha256};
+
+    #[test]
+    fn known_digests() {
+        assert_eq!(
+            hex(&sha256(b"")),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855",
+        );
+        assert_eq!(
+            hex(&sha256(b"abc")),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad",
+        );
+        assert_eq!(
+            hex(&sha256(
+                b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq",
+            )),
+            "248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1",
+        );
+    }
+
+    #[test]
+    fn hmac() {
+        // Test cases 1, 2, and 6 of RFC 4231.
+        assert_eq!(
+            hex(&hmac_sha256(&[0x0b; 20], b"Hi There")),
+            "b0344c61d8db38535ca8afceaf0bf12b881dc200c9833da726e9376c2e32cff7",
+        );
+        assert_eq!(
+            hex(&hmac_sha256(b"Jefe", b"what do ya want for nothing?")),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843",
+        );
+        assert_eq!(
+            hex(&hmac_sha256(
+                &[0xaa; 131],
+                b"Test Using Larger Than Block-Size Key - Hash Key First",
+            )),
+            "60e431591ee0b67f0d8a26aacbf5b77f8e0bc6213728c5140546040f0ee37f54",
+        );
+    }
+
+    #[test]
+    fn incremental() {
+        // Cross block boundaries, and the padding's.
+        let data: Vec<u8> = (0..1000u32).map(|i| i as u8).collect();
+        for &size in &[1, 55, 56, 63, 64, 65, 1000] {
+            let mut hasher = Sha256::new();
+            for chunk in data[..size].chunks(7) {
+                hasher.update(chunk);
+            }
+            assert_eq!(hasher.finish(), sha256(&data[..size]));
+        }
+        let million = vec![b'a'; 1_000_000];
+        assert_eq!(
+            hex(&sha256(&million)),
+            "cdc76e5c9914fb9281a1c7e284d73e67f1809a48a497200e046d39ccc7112cd0",
+        );
+    }
+}
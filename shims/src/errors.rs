@@ -0,0 +1,250 @@
+//! Errors raised by the shim itself.
+//!
+//! Each error maps to an exit code, so callers can tell a broken shim from a
+//! failing child process. The codes are chosen from the high range usually
+//! left alone by programs (including Python):
+//!
+//! | Code | Error                                           |
+//! |------|-------------------------------------------------|
+//! | 127  | The command's executable does not exist, or the |
+//! |      | pinned version is not installed.                |
+//! | 126  | The command's executable cannot be launched,    |
+//! |      | its working directory cannot be entered, or the |
+//! |      | lock of a detached command cannot be taken.     |
+//! | 125  | The shim's data cannot be read, or are corrupt. |
+//! | 124  | The shim contains no commands.                  |
+//! | 123  | The shim process cannot be set up.              |
+//! | 122  | The shim lost track of the child process.       |
+//! | 121  | The command's executable changed since the shim |
+//! |      | was written, and the shim refuses to run it.    |
+//! | 120  | The shim's data are not signed with the user's  |
+//! |      | key, or the key cannot be read.                 |
+//! | 119  | The shim runs itself, or shims are nested too   |
+//! |      | deeply.                                         |
+
+use std::{error, fmt, io};
+use std::path::PathBuf;
+
+pub const EXIT_NOT_FOUND: i32 = 127;
+pub const EXIT_SPAWN: i32 = 126;
+pub const EXIT_CORRUPT: i32 = 125;
+pub const EXIT_EMPTY: i32 = 124;
+pub const EXIT_SETUP: i32 = 123;
+pub const EXIT_WAIT: i32 = 122;
+pub const EXIT_CHANGED: i32 = 121;
+pub const EXIT_UNAUTHENTICATED: i32 = 120;
+pub const EXIT_RECURSION: i32 = 119;
+
+#[derive(Debug)]
+pub enum Error {
+    /// The shim executable cannot be read.
+    Read(io::Error),
+
+    /// Data embedded in the shim are malformed.
+    Corrupt(String),
+
+    /// The shim does not contain any commands.
+    Empty,
+
+    /// The shim process cannot be set up to manage its child.
+    Setup(&'static str),
+
+    /// The child process cannot be launched.
+    Spawn(PathBuf, io::Error),
+
+    /// The command's executable does not exist. This usually means the
+    /// interpreter was uninstalled, but the shim not removed. The name of the
+    /// version the shim is for is included, if known.
+    Missing(PathBuf, Option<String>),
+
+    /// The version pinned for the working directory is not installed. The
+    /// name of the version and where it is pinned are included.
+    NotInstalled(String, String),
+
+    /// The command's working directory is missing, or not a directory.
+    WorkingDir(PathBuf, io::Error),
+
+    /// The lock file of a detached command cannot be opened or locked.
+    Lock(PathBuf, io::Error),
+
+    /// The child process cannot be waited for.
+    Wait(io::Error),
+
+    /// The command's executable does not match the digest recorded in the
+    /// shim, e.g. because another Python was installed over it.
+    Changed(PathBuf),
+
+    /// The user has a key, but the shim's data are not signed with it,
+    /// e.g. because they were changed to run something else.
+    Unauthenticated(String),
+
+    /// The command's executable is the shim itself.
+    RunsItself(PathBuf),
+
+    /// Shims running each other are nested deeper than allowed, likely
+    /// because one runs itself indirectly. The depth is included.
+    TooDeep(u32),
+}
+
+impl Error {
+    pub fn exit_code(&self) -> i32 {
+        match *self {
+            Error::Read(_) | Error::Corrupt(_) => EXIT_CORRUPT,
+            Error::Empty => EXIT_EMPTY,
+            Error::Setup(_) => EXIT_SETUP,
+            Error::Spawn(_, ref e) => match e.kind() {
+                io::ErrorKind::NotFound => EXIT_NOT_FOUND,
+                _ => EXIT_SPAWN,
+            },
+            Error::Missing(_, _) | Error::NotInstalled(_, _) => {
+                EXIT_NOT_FOUND
+            },
+            Error::WorkingDir(_, _) | Error::Lock(_, _) => EXIT_SPAWN,
+            Error::Wait(_) => EXIT_WAIT,
+            Error::Changed(_) => EXIT_CHANGED,
+            Error::Unauthenticated(_) => EXIT_UNAUTHENTICATED,
+            Error::RunsItself(_) | Error::TooDeep(_) => EXIT_RECURSION,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Read(ref e) => write!(f, "failed to read shim: {}", e),
+            Error::Corrupt(ref message) => write!(f, "bad shim: {}", message),
+            Error::Empty => write!(f, "bad shim: no commands"),
+            Error::Setup(message) => {
+                write!(f, "failed to set up shim: {}", message)
+            },
+            Error::Spawn(ref exe, ref e) => {
+                write!(f, "failed to spawn {}: {}", exe.display(), e)
+            },
+            Error::Missing(ref exe, ref version) => {
+                writeln!(f, "interpreter not found: {}", exe.display())?;
+                let version = match *version {
+                    Some(ref v) => v.as_str(),
+                    None => "<version>",
+                };
+                write!(f,
+                    "HINT: Run \"pythonup install {}\" to install it, or \
+                     \"pythonup link --all\" to update commands.",
+                    version,
+                )
+            },
+            Error::NotInstalled(ref name, ref source) => {
+                writeln!(f,
+                    "version {} pinned by {} is not installed", name, source,
+                )?;
+                write!(f,
+                    "HINT: Run \"pythonup install {}\" to install it.", name,
+                )
+            },
+            Error::WorkingDir(ref dir, ref e) => write!(f,
+                "failed to enter working directory {}: {}", dir.display(), e,
+            ),
+            Error::Lock(ref path, ref e) => {
+                write!(f, "failed to lock {}: {}", path.display(), e)
+            },
+            Error::Wait(ref e) => {
+                write!(f, "failed to wait for child: {}", e)
+            },
+            Error::Changed(ref exe) => {
+                writeln!(f,
+                    "executable changed since the shim was written: {}",
+                    exe.display(),
+                )?;
+                write!(f, "HINT: Write the shim again if this is expected.")
+            },
+            Error::Unauthenticated(ref message) => {
+                writeln!(f, "shim failed authentication: {}", message)?;
+                write!(f,
+                    "HINT: The shim may have been tampered with. Write it \
+                     again with \"shim-tool create --sign\" if it is trusted.",
+                )
+            },
+            Error::RunsItself(ref exe) => {
+                writeln!(f, "shim runs itself: {}", exe.display())?;
+                write!(f,
+                    "HINT: Run \"pythonup link --all\" to update commands.",
+                )
+            },
+            Error::TooDeep(depth) => {
+                writeln!(f, "shims nested too deeply ({} levels)", depth)?;
+                write!(f,
+                    "HINT: A shim probably runs itself through others, e.g. \
+                     found in PATH. Check with \"shim-tool inspect\".",
+                )
+            },
+        }
+    }
+}
+
+impl error::Error for Error {}
+
+impl From<io::Error> for Error {
+    /// Invalid data are considered corrupt, other errors as failed reads.
+    fn from(e: io::Error) -> Self {
+        match e.kind() {
+            io::ErrorKind::InvalidData => Error::Corrupt(e.to_string()),
+            _ => Error::Read(e),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod error_test {
+    use std::io;
+    use std::path::PathBuf;
+    use super::Error;
+
+    #[test]
+    fn exit_codes() {
+        let missing = io::Error::new(io::ErrorKind::NotFound, "missing");
+        let denied = io::Error::new(io::ErrorKind::PermissionDenied, "no");
+        let exe = PathBuf::from("C:\\python.exe");
+        assert_eq!(Error::Spawn(exe.clone(), missing).exit_code(), 127);
+        assert_eq!(Error::Spawn(exe.clone(), denied).exit_code(), 126);
+        let denied = io::Error::new(io::ErrorKind::PermissionDenied, "no");
+        assert_eq!(Error::Lock(exe, denied).exit_code(), 126);
+        assert_eq!(Error::Corrupt(String::from("x")).exit_code(), 125);
+        assert_eq!(Error::Empty.exit_code(), 124);
+        assert_eq!(Error::Setup("x").exit_code(), 123);
+    }
+
+    #[test]
+    fn from_io() {
+        let e = io::Error::new(io::ErrorKind::InvalidData, "bad checksum");
+        match Error::from(e) {
+            Error::Corrupt(message) => assert_eq!(message, "bad checksum"),
+            e => panic!("unexpected {:?}", e),
+        }
+        let e = io::Error::new(io::ErrorKind::PermissionDenied, "denied");
+        match Error::from(e) {
+            Error::Read(_) => {},
+            e => panic!("unexpected {:?}", e),
+        }
+    }
+
+    #[test]
+    fn missing() {
+        let exe = PathBuf::from("/py/python");
+        let e = Error::Missing(exe.clone(), Some(String::from("3.7")));
+        assert_eq!(e.exit_code(), 127);
+        assert_eq!(e.to_string(), "\
+            interpreter not found: /py/python\n\
+            HINT: Run \"pythonup install 3.7\" to install it, or \
+            \"pythonup link --all\" to update commands.");
+
+        let e = Error::Missing(exe, None);
+        assert!(e.to_string().contains("pythonup install <version>"));
+    }
+
+    #[test]
+    fn display() {
+        let e = io::Error::new(io::ErrorKind::NotFound, "not found");
+        let e = Error::Spawn(PathBuf::from("/py/python"), e);
+        assert_eq!(e.to_string(), "failed to spawn /py/python: not found");
+    }
+}
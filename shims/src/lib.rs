@@ -0,0 +1,13 @@
+pub mod auth;
+pub mod cmds;
+pub mod digest;
+pub mod errors;
+pub mod procs;
+pub mod relink;
+pub mod resolve;
+pub mod shebang;
+#[cfg(test)] mod testing;
+pub mod trailer;
+pub mod verify;
+pub mod writer;
+pub mod zipapp;
@@ -1,31 +1,50 @@
-mod cmds;
-mod procs;
+extern crate shim;
 
-use std::process::{abort, exit};
-use self::cmds::Commands;
-use self::procs::{setup, run};
+use std::env;
+use std::path::Path;
+use std::process::exit;
+use shim::cmds::Commands;
+use shim::errors::Error;
+use shim::procs::{exec, guard, run_all, run_detached, setup, DETACHED_ENV};
+use shim::resolve::executable;
+use shim::verify::enforce;
 
-macro_rules! run_or_exit {
-    ( $cmd:expr, $own_args:expr ) => {
-        match run($cmd.exe().unwrap(), $cmd.args(), $own_args) {
-            Ok(code) => if code != 0 { exit(code) },
-            Err(e) => { eprintln!("{}", e); abort(); },
-        }
-    };
+// Report an error from the shim itself, and exit with its code.
+fn fail(e: Error) -> ! {
+    eprintln!("{}", e);
+    exit(e.exit_code());
 }
 
 fn main() {
-    let mut cmds = Commands::from_current_exe().unwrap_or_else(|e| {
-        eprintln!("{}", e);
-        abort();
-    });
+    // The shim started itself to run a detached command.
+    if let Some(lock) = env::var_os(DETACHED_ENV) {
+        match run_detached(Path::new(&lock), env::args_os().skip(1)) {
+            Ok(()) => exit(0),
+            Err(e) => fail(e),
+        }
+    }
+
+    let mut cmds = Commands::from_current_exe().unwrap_or_else(|e| fail(e));
+
+    setup().unwrap_or_else(|e| fail(e));
+
+    // Nothing to do after a lone command, so just become it if possible.
+    if cmds.len() == 1 && !cmds.as_slice()[0].detached() {
+        let cmd = cmds.next().unwrap();
+        if !cmd.condition().allows(0) {
+            exit(0);
+        }
+        let exe = executable(&cmd, cmds.options()).unwrap_or_else(|e| fail(e));
+        enforce(&exe, &cmd, cmds.options()).unwrap_or_else(|e| fail(e));
+        guard(&exe, cmds.options()).unwrap_or_else(|e| fail(e));
+        match exec(&exe, &cmd, cmd.appends_own_args(), cmds.options()) {
+            Ok(code) => exit(code),
+            Err(e) => fail(e),
+        }
+    }
 
-    setup().unwrap_or_else(|e| { eprintln!("{}", e); abort(); });
-    match cmds.next() {
-        Some(cmd) => run_or_exit!(cmd, true),
-        None => { abort(); },
-    };
-    for cmd in cmds {
-        run_or_exit!(cmd, false);
+    match run_all(cmds) {
+        Ok(code) => exit(code),
+        Err(e) => fail(e),
     }
 }
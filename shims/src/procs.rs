@@ -1,121 +1,962 @@
-/// Interface to run a child process for the shim.
+//! Interface to run a child process for the shim.
+//!
+//! Process handling differs a lot between platforms, and is implemented by a
+//! `Launcher` for each of them. The free functions in this module use the
+//! launcher for the current platform.
+
+use std::env;
+use std::ffi::{OsStr, OsString};
+use std::fs::{self, File};
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use std::process::Command;
+use std::time::SystemTime;
+
+use cmds::{self, Commands, EnvOp, Options};
+use errors::Error;
+use resolve;
+use trailer;
+use verify;
+
+#[cfg(unix)] mod unix;
+#[cfg(windows)] mod windows;
+
+#[cfg(unix)] pub use self::unix::UnixLauncher as PlatformLauncher;
+#[cfg(windows)] pub use self::windows::WindowsLauncher as PlatformLauncher;
+
+pub trait Launcher {
+    /// Set up the parent process.
+    ///
+    /// This should be called before running the child, so the parent passes
+    /// on interruptions to its child, instead of exiting on them.
+    fn setup(&self) -> Result<(), Error>;
+
+    /// Launch the child process, wait for it, and return its exit code.
+    ///
+    /// The child is tied to the parent, so it does not outlive the parent.
+    fn launch(&self, cmd: &mut Command) -> Result<i32, Error>;
+
+    /// Replace the current process with the child.
+    ///
+    /// This only returns if the process cannot be replaced. Platforms without
+    /// such a facility launch the child and wait for it instead.
+    fn exec(&self, cmd: &mut Command) -> Result<i32, Error> {
+        self.launch(cmd)
+    }
+
+    /// Start the child process in the background, and return immediately.
+    ///
+    /// The child is detached from the parent, so it is left alone by
+    /// interruptions, and outlives the parent.
+    fn detach(&self, cmd: &mut Command) -> Result<(), Error>;
+
+    /// Lock the file at `path`, creating it if needed, until the returned
+    /// file is closed. `None` is returned if another process holds it.
+    fn try_lock(&self, path: &Path) -> Result<Option<File>, Error>;
+}
+
+/// Guess the version a shim is for from its name.
 ///
-/// Most of this module, especially the part setting up the shild process, is
-/// based on how Pip creates an EXE launcher for console scripts, from
-/// [distlib], developed in C by Vinay Sajip.
+/// Shims in the cmd directory are named like `python3.7.exe` or
+/// `pip3.7-32.exe`. Other names, e.g. `python3.exe`, are not specific to a
+/// version, and `None` is returned.
+pub fn version_name(shim: &Path) -> Option<String> {
+    let stem = cmds::shim_name(shim)?;
+    let name = stem.strip_prefix("python")
+        .or_else(|| stem.strip_prefix("pip"))?;
+    let version = match name.split_once('-') {
+        Some((version, "32")) => version,
+        Some(_) => { return None; },
+        None => name,
+    };
+    let parts: Vec<_> = version.split('.').collect();
+    let numeric = |p: &&str| {
+        !p.is_empty() && p.chars().all(|c| c.is_ascii_digit())
+    };
+    if parts.len() != 2 || !parts.iter().all(numeric) {
+        return None;
+    }
+    Some(String::from(name))
+}
+
+/// Check the executable exists before launching it.
 ///
-/// [distlib]: https://github.com/vsajip/distlib/blob/master/PC/launcher.c
+/// Only paths are checked. Bare names are left for the OS to look up.
+fn check_exe(exe: &Path) -> Result<(), Error> {
+    let is_path = exe.parent().is_some_and(|p| !p.as_os_str().is_empty());
+    if is_path && !exe.exists() {
+        let version = env::current_exe().ok().and_then(|p| version_name(&p));
+        return Err(Error::Missing(exe.to_path_buf(), version));
+    }
+    Ok(())
+}
 
-extern crate winapi;
+/// Environment variable with the number of shims the process runs under.
+pub const DEPTH_ENV: &str = "PYTHONUP_SHIM_DEPTH";
 
-use std::{env, mem};
-use std::os::windows::io::AsRawHandle;
-use std::path::PathBuf;
-use std::process::{Child, Command};
+// Number of shims running the current process, not counting itself.
+fn depth() -> u32 {
+    env::var(DEPTH_ENV).ok().and_then(|v| v.parse().ok()).unwrap_or(0)
+}
 
-use self::winapi::ctypes::c_void;
-use self::winapi::shared::minwindef::{BOOL, DWORD, LPVOID, TRUE};
-use self::winapi::um::consoleapi::SetConsoleCtrlHandler;
-use self::winapi::um::jobapi2::{
-    AssignProcessToJobObject, CreateJobObjectW, QueryInformationJobObject,
-    SetInformationJobObject};
-use self::winapi::um::wincon::{CTRL_C_EVENT, GenerateConsoleCtrlEvent};
-use self::winapi::um::winnt::{
-    JOBOBJECT_EXTENDED_LIMIT_INFORMATION,
-    JOB_OBJECT_LIMIT_KILL_ON_JOB_CLOSE, JOB_OBJECT_LIMIT_SILENT_BREAKAWAY_OK,
-    JobObjectExtendedLimitInformation};
+fn check_recursion(exe: &Path, shim: &Path, depth: u32, options: &Options)
+        -> Result<(), Error> {
+    if depth >= u32::from(options.max_depth()) {
+        return Err(Error::TooDeep(depth));
+    }
+    match (fs::canonicalize(exe), fs::canonicalize(shim)) {
+        (Ok(ref exe), Ok(ref shim)) if exe == shim => {
+            Err(Error::RunsItself(exe.clone()))
+        },
+        _ => Ok(()),
+    }
+}
 
+/// Check running the executable does not recurse endlessly.
+///
+/// This refuses to run the shim itself, or to nest shims deeper than they
+/// allow. Executables given by bare name are not looked up, but are still
+/// caught by the depth limit.
+pub fn guard(exe: &Path, options: &Options) -> Result<(), Error> {
+    let shim = env::current_exe().map_err(Error::Read)?;
+    check_recursion(exe, &shim, depth(), options)
+}
 
-static mut PID: u32 = 0;
+#[cfg(windows)] const PATH_SEPARATOR: &str = ";";
+#[cfg(not(windows))] const PATH_SEPARATOR: &str = ":";
 
-unsafe extern "system" fn handle_ctrl(etype: DWORD) -> BOOL {
-    if etype == CTRL_C_EVENT && PID != 0 {
-        // FIXME: Why does this work? The two arguments of
-        // GenerateConsoleCtrlEvent are dwCtrlEvent and dwProcessGroupId, so
-        // we're passing them backwards... But this is what what Python's
-        // launchers do, and IT ACTUALLY WORKS. I'm letting it stand for now.
-        GenerateConsoleCtrlEvent(PID, CTRL_C_EVENT);
+// Whether two environment variable names are the same. They are
+// case-insensitive on Windows.
+#[cfg(windows)]
+fn same_var(a: &OsStr, b: &OsStr) -> bool {
+    match (a.to_str(), b.to_str()) {
+        (Some(a), Some(b)) => a.to_uppercase() == b.to_uppercase(),
+        _ => a == b,
     }
-    TRUE
 }
 
-unsafe fn setup_child(child: &mut Child) -> Result<(), &'static str> {
-    PID = child.id();
+#[cfg(not(windows))]
+fn same_var(a: &OsStr, b: &OsStr) -> bool {
+    a == b
+}
+
+// Value of a variable in the child's environment so far.
+fn child_var(cmd: &Command, name: &OsStr) -> Option<OsString> {
+    match cmd.get_envs().find(|&(key, _)| same_var(key, name)) {
+        Some((_, value)) => value.map(OsStr::to_os_string),
+        None => env::var_os(name),
+    }
+}
 
-    let job = CreateJobObjectW(0 as *mut _, 0 as *const _);
-    let mut info: JOBOBJECT_EXTENDED_LIMIT_INFORMATION = mem::zeroed();
+fn apply_env(cmd: &mut Command, ops: &[EnvOp]) {
+    for op in ops {
+        match *op {
+            EnvOp::Set(ref name, ref value) => { cmd.env(name, value); },
+            EnvOp::Unset(ref name) => { cmd.env_remove(name); },
+            EnvOp::PrependPath(ref name, ref entry) => {
+                let mut value = entry.clone();
+                if let Some(old) = child_var(cmd, name) {
+                    if !old.is_empty() {
+                        value.push(PATH_SEPARATOR);
+                        value.push(old);
+                    }
+                }
+                cmd.env(name, value);
+            },
+        }
+    }
+}
 
-    let mut ok;
+// Directories of the installation an executable is in: the one containing
+// it, or its `Scripts` (`bin` on Unix), and these subdirectories.
+fn installation_dirs(exe: &Path) -> Vec<PathBuf> {
+    let parent = match exe.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => { return vec![]; },
+    };
+    let in_subdir = parent.file_name().and_then(OsStr::to_str)
+        .is_some_and(|name| name.eq_ignore_ascii_case("Scripts")
+                     || name == "bin");
+    let base = match parent.parent() {
+        Some(base) if in_subdir => base,
+        _ => parent,
+    };
+    let mut dirs = vec![base.to_path_buf()];
+    for name in &["Scripts", "bin"] {
+        let dir = base.join(name);
+        if dir.is_dir() {
+            dirs.push(dir);
+        }
+    }
+    dirs
+}
 
-    ok = QueryInformationJobObject(
-        job, JobObjectExtendedLimitInformation,
-        &mut info as *mut _ as LPVOID,
-        mem::size_of_val(&info) as DWORD,
-        0 as *mut _,
-    );
-    if ok != TRUE {
-        return Err("job information query error");
+// A directory with `.` and `..` resolved lexically, to compare it without
+// touching the file system. Names are case-insensitive on Windows.
+fn dir_key(dir: &Path) -> PathBuf {
+    let mut key = PathBuf::new();
+    for component in dir.components() {
+        match component {
+            Component::CurDir => {},
+            Component::ParentDir if matches!(
+                    key.components().next_back(),
+                    Some(Component::Normal(_))) => { key.pop(); },
+            _ => { key.push(component.as_os_str()); },
+        }
+    }
+    match key.to_str() {
+        Some(s) if cfg!(windows) => PathBuf::from(s.to_lowercase()),
+        _ => key,
     }
+}
 
-    info.BasicLimitInformation.LimitFlags =
-        info.BasicLimitInformation.LimitFlags |
-        JOB_OBJECT_LIMIT_KILL_ON_JOB_CLOSE |
-        JOB_OBJECT_LIMIT_SILENT_BREAKAWAY_OK;
-    ok = SetInformationJobObject(
-        job, JobObjectExtendedLimitInformation,
-        &mut info as *mut _ as LPVOID,
-        mem::size_of_val(&info) as DWORD,
-    );
-    if ok != TRUE {
-        return Err("job information set error");
+// Directories of shims, to leave out of interpreters' `PATH`: the running
+// shim's, and pythonup's `cmd` and `Scripts` if the shim knows them.
+fn shim_dirs(options: &Options) -> Vec<PathBuf> {
+    let mut dirs = vec![];
+    let shim = env::current_exe().ok();
+    dirs.extend(shim.as_ref().and_then(|shim| shim.parent()).map(
+        Path::to_path_buf,
+    ));
+    if let Some(installation) = options.installation() {
+        for key in &["cmd_dir", "scripts_dir"] {
+            dirs.extend(resolve::configured_dir(installation, key));
+        }
     }
+    dirs
+}
 
-    AssignProcessToJobObject(job, child.as_raw_handle() as *mut c_void);
+// `PATH` leading to the executable's installation first, without shim
+// directories. Executables given by bare name only lose the latter.
+fn installation_path(exe: &Path, shim_dirs: &[PathBuf],
+                     path: Option<OsString>) -> Option<OsString> {
+    // Entries are compared as written, and shim directories also as they
+    // really are, e.g. if the shim is run through a link.
+    let mut keys = vec![];
+    for dir in shim_dirs {
+        keys.push(dir_key(dir));
+        keys.extend(fs::canonicalize(dir).ok().map(|dir| dir_key(&dir)));
+    }
+    let is_shim_dir = |entry: &PathBuf| keys.contains(&dir_key(entry));
+    let mut entries = installation_dirs(exe);
+    if let Some(ref path) = path {
+        entries.extend(env::split_paths(path).filter(|e| !is_shim_dir(e)));
+    }
+    env::join_paths(entries).ok()
+}
 
-    Ok(())
+// Check the directory to run a command in can be entered.
+fn check_working_dir(dir: &Path) -> Result<(), Error> {
+    match fs::metadata(dir) {
+        Ok(ref metadata) if metadata.is_dir() => Ok(()),
+        Ok(_) => {
+            let e = io::Error::new(
+                io::ErrorKind::NotADirectory, "not a directory",
+            );
+            Err(Error::WorkingDir(dir.to_path_buf(), e))
+        },
+        Err(e) => Err(Error::WorkingDir(dir.to_path_buf(), e)),
+    }
+}
+
+fn build_command(exe: &Path, command: &cmds::Command, with_own_args: bool,
+                 options: &Options) -> Result<Command, Error> {
+    let mut cmd = Command::new(exe);
+    cmd.args(command.args());
+    cmd.env(DEPTH_ENV, (depth() + 1).to_string());
+    if command.prepends_installation() {
+        if let Some(path) = installation_path(
+                exe, &shim_dirs(options), env::var_os("PATH")) {
+            cmd.env("PATH", path);
+        }
+    }
+    apply_env(&mut cmd, command.env());
+    if let Some(dir) = command.working_dir() {
+        check_working_dir(dir)?;
+        cmd.current_dir(dir);
+    }
+
+    // Hand over arguments passed to the shim (args[0] not included).
+    if with_own_args {
+        cmd.args(env::args_os().skip(1));
+    }
+    Ok(cmd)
 }
 
 /// Run the child process, and return its result.
 ///
-/// The child's exit code is returned, or an error message if the child fails
-/// to launch, or does not exit cleanly. If `with_own_args` is `true`, the
+/// `exe` is launched with the command's arguments, environment, and working
+/// directory. It is passed separately since the command's own executable may
+/// be resolved to another one.
+///
+/// The child's exit code is returned, or an error if the child fails to
+/// launch, or does not exit cleanly. If `with_own_args` is `true`, the
 /// child process is launched with arguments passed to the parent process,
-/// appende after `args`.
-pub fn run(exe: &PathBuf, args: &Vec<String>, with_own_args: bool)
-        -> Result<i32, String> {
+/// appende after the command's. `options` are the shim's.
+pub fn run(exe: &Path, command: &cmds::Command, with_own_args: bool,
+           options: &Options) -> Result<i32, Error> {
+    check_exe(exe)?;
+    let mut cmd = build_command(exe, command, with_own_args, options)?;
+    PlatformLauncher.launch(&mut cmd)
+}
 
-    let mut cmd = Command::new(exe);
+/// Replace the current process with the child process.
+///
+/// Arguments are handled as in `run`. This only returns on platforms unable
+/// to replace the process, or if the child fails to launch.
+pub fn exec(exe: &Path, command: &cmds::Command, with_own_args: bool,
+            options: &Options) -> Result<i32, Error> {
+    check_exe(exe)?;
+    let mut cmd = build_command(exe, command, with_own_args, options)?;
+    PlatformLauncher.exec(&mut cmd)
+}
+
+/// Environment variable telling the shim to run its arguments as a detached
+/// command, queued with others by the lock file it names.
+pub const DETACHED_ENV: &str = "PYTHONUP_SHIM_DETACHED";
+
+// Lock file shared by detached children with the same command line.
+fn lock_path(cmd: &Command) -> PathBuf {
+    let mut strings = vec![cmd.get_program()];
+    strings.extend(cmd.get_args());
+    let hash = trailer::checksum(&trailer::encode_os_strs(&strings));
+    env::temp_dir().join(format!("pythonup-shim-{:08x}.lock", hash))
+}
+
+// File marking a detached command as due to run (again).
+fn pending_path(lock: &Path) -> PathBuf {
+    lock.with_extension("pending")
+}
+
+fn queue(lock: &Path) -> Result<(), Error> {
+    let pending = pending_path(lock);
+    fs::write(&pending, b"").map_err(|e| Error::Lock(pending, e))
+}
+
+// Take the command off the queue, returning whether it was queued.
+fn dequeue(lock: &Path) -> Result<bool, Error> {
+    let pending = pending_path(lock);
+    match fs::remove_file(&pending) {
+        Ok(()) => Ok(true),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(Error::Lock(pending, e)),
+    }
+}
+
+// The shim running `cmd` detached, with its arguments and environment.
+fn runner_command(runner: &Path, cmd: &Command, lock: &Path) -> Command {
+    let mut runner = Command::new(runner);
+    runner.arg(cmd.get_program());
+    runner.args(cmd.get_args());
+    for (name, value) in cmd.get_envs() {
+        match value {
+            Some(value) => { runner.env(name, value); },
+            None => { runner.env_remove(name); },
+        }
+    }
+    if let Some(dir) = cmd.get_current_dir() {
+        runner.current_dir(dir);
+    }
+    runner.env(DETACHED_ENV, lock);
+    runner
+}
+
+/// Start the child process in the background.
+///
+/// Arguments are handled as in `run`. The shim starts itself detached to
+/// run the child, queued after the same command already running in the
+/// background (see `run_detached`), so each runs after the last request.
+pub fn detach(exe: &Path, command: &cmds::Command, with_own_args: bool,
+              options: &Options) -> Result<(), Error> {
+    check_exe(exe)?;
+    let cmd = build_command(exe, command, with_own_args, options)?;
+    let lock = lock_path(&cmd);
+    let runner = env::current_exe().map_err(Error::Read)?;
+    queue(&lock)?;
+    PlatformLauncher.detach(&mut runner_command(&runner, &cmd, &lock))
+}
+
+/// Run a command detached by `detach`, for as long as it is queued.
+///
+/// `args` are the command's executable and arguments. Only one process runs
+/// the same command at a time, holding the `lock` file. Others leave the
+/// command queued, and return immediately, so the holder runs it once
+/// more after the current run, however many times it is queued meanwhile.
+pub fn run_detached<I>(lock: &Path, args: I) -> Result<(), Error>
+        where I: IntoIterator<Item=OsString> {
+    let mut args = args.into_iter();
+    let mut cmd = Command::new(args.next().ok_or(Error::Empty)?);
     cmd.args(args);
+    cmd.env_remove(DETACHED_ENV);
+    loop {
+        let file = match PlatformLauncher.try_lock(lock)? {
+            Some(file) => file,
+            None => { return Ok(()); },
+        };
+        while dequeue(lock)? {
+            PlatformLauncher.launch(&mut cmd)?;
+        }
+        drop(file);
 
-    // Hand over arguments passed to the shim (args[0] not included).
-    if with_own_args {
-        cmd.args(env::args().skip(1));
+        // Another shim may have failed to take the lock just before it was
+        // released, and left the command queued.
+        if !pending_path(lock).exists() {
+            return Ok(());
+        }
     }
+}
 
-    let mut child = cmd.spawn().map_err(|e| {
-        format!("failed to spawn child: {}", e)
-    })?;
+// Entries of a directory, with their sizes and modification times, or
+// `None` if the directory cannot be read.
+fn fingerprint(dir: &Path) -> Option<Vec<(OsString, u64, SystemTime)>> {
+    let mut entries = vec![];
+    for entry in fs::read_dir(dir).ok()? {
+        let entry = entry.ok()?;
+        let metadata = entry.metadata().ok()?;
+        let modified = metadata.modified().ok()?;
+        entries.push((entry.file_name(), metadata.len(), modified));
+    }
+    entries.sort();
+    Some(entries)
+}
 
-    unsafe { setup_child(&mut child)? };
+/// Run commands in sequence, and return the exit code to report.
+///
+/// Each command runs or is skipped depending on its condition. Detached
+/// commands are started without waiting for them, and do not affect
+/// conditions or the exit code. If the shim watches a directory, commands
+/// after the first are skipped if the first does not change it. Arguments
+/// passed to the shim are appended to the first command if it allows. The
+/// exit code is chosen among commands run, as configured by the shim. Errors
+/// from the shim itself stop the sequence.
+pub fn run_all(mut commands: Commands) -> Result<i32, Error> {
+    let watch_dir = commands.options().watch_dir().cloned();
+    let before = watch_dir.as_ref().and_then(|dir| fingerprint(dir));
 
-    let result = child.wait().map_err(|e| {
-        format!("failed to wait for child: {}", e)
-    })?;
+    let mut codes = vec![];
+    let mut last = 0;
+    let mut first = true;
+    while let Some(command) = commands.next() {
+        let with_own_args = first && command.appends_own_args();
+        let is_first = first;
+        first = false;
+        if !command.condition().allows(last) {
+            continue;
+        }
+        let exe = resolve::executable(&command, commands.options())?;
+        verify::enforce(&exe, &command, commands.options())?;
+        guard(&exe, commands.options())?;
+        if command.detached() {
+            detach(&exe, &command, with_own_args, commands.options())?;
+            continue;
+        }
+        last = run(&exe, &command, with_own_args, commands.options())?;
+        codes.push(last);
 
-    // Doc seems to suggest this won't happen on Windows, but I'm not sure.
-    // 137 is a common value seen with SIGKILL terminated programs.
-    Ok(result.code().unwrap_or(137))
+        // An unreadable directory is considered changed.
+        let unchanged = |dir: &PathBuf| {
+            before.is_some() && fingerprint(dir) == before
+        };
+        if is_first && watch_dir.as_ref().is_some_and(unchanged) {
+            break;
+        }
+    }
+    if first {
+        return Err(Error::Empty);
+    }
+    Ok(commands.options().report().choose(&codes))
 }
 
 /// Set up the parent process.
-///
-/// This should be called before running the child, to set up the CTRL handler
-/// so the parent passes on the CTRL-C event to its child.
-pub fn setup() -> Result<(), &'static str> {
-    let ok = unsafe { SetConsoleCtrlHandler(Some(handle_ctrl), TRUE) };
-    if ok == TRUE {
-        Ok(())
-    } else {
-        Err("control handler set error")
+pub fn setup() -> Result<(), Error> {
+    PlatformLauncher.setup()
+}
+
+
+#[cfg(test)]
+mod procs_test {
+    use std::env;
+    #[cfg(unix)] use std::fs;
+    use std::path::{Path, PathBuf};
+    use cmds::{Command, Commands, Options};
+    #[cfg(unix)] use cmds::{Condition, EnvOp, Report};
+    use errors::Error;
+    use testing::TempDir;
+    use writer::write_shim;
+    use super::{
+        check_recursion, check_working_dir, installation_path, lock_path,
+        run, run_all, version_name};
+
+    #[test]
+    fn version_names() {
+        let name = |s: &str| version_name(Path::new(s));
+        assert_eq!(name("python3.7.exe"), Some(String::from("3.7")));
+        assert_eq!(name("pip3.10.exe"), Some(String::from("3.10")));
+        assert_eq!(name("python3.7-32.exe"), Some(String::from("3.7-32")));
+        assert_eq!(name("/cmd/pip2.7"), Some(String::from("2.7")));
+        assert_eq!(name("PYTHON3.8.EXE"), None);
+        assert_eq!(name("python3.8.EXE"), Some(String::from("3.8")));
+        assert_eq!(name("python3.exe"), None);
+        assert_eq!(name("python3.7-64.exe"), None);
+        assert_eq!(name("python3.x.exe"), None);
+        assert_eq!(name("black.exe"), None);
+        assert_eq!(name("pip.exe"), None);
+    }
+
+    #[test]
+    fn missing_exe() {
+        let dir = TempDir::new("procs-missing");
+        let base = dir.write("shim", b"MZ");
+        let shim = dir.join("shim.exe");
+
+        let mut command = Command::new();
+        command.arg("/nonexistent/python3.7/python");
+        let options = Options::default();
+        write_shim(&base, &shim, &[command], &options, None).unwrap();
+
+        let command = Commands::from_path(&shim).unwrap().next().unwrap();
+        let options = Options::default();
+        let result = run(command.exe().unwrap(), &command, false, &options);
+        let expected = PathBuf::from("/nonexistent/python3.7/python");
+        match result {
+            Err(Error::Missing(exe, _)) => assert_eq!(exe, expected),
+            r => panic!("unexpected {:?}", r),
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn runs_itself() {
+        use std::os::unix::fs::symlink;
+
+        let dir = TempDir::new("procs-itself");
+        let base = dir.write("base", b"MZ");
+        let shim = dir.join("python3");
+        symlink(&shim, dir.join("link")).unwrap();
+
+        // A trailer pointing to the shim, directly or through a symlink.
+        let options = Options::default();
+        let check = |exe: &str| {
+            let mut command = Command::new();
+            command.arg(dir.join(exe));
+            write_shim(&base, &shim, &[command], &options, None).unwrap();
+            let command = Commands::from_path(&shim).unwrap().next().unwrap();
+            check_recursion(command.exe().unwrap(), &shim, 0, &options)
+        };
+        let itself = check("python3");
+        let through_link = check("link");
+        let other = check("base");
+        let canonical = fs::canonicalize(&shim).unwrap();
+
+        for result in &[itself, through_link] {
+            match *result {
+                Err(ref e @ Error::RunsItself(ref exe)) => {
+                    assert_eq!(exe, &canonical);
+                    assert_eq!(e.exit_code(), 119);
+                },
+                ref r => panic!("unexpected {:?}", r),
+            }
+        }
+        assert!(other.is_ok());
+    }
+
+    #[test]
+    fn too_deep() {
+        let exe = Path::new("/usr/bin/python3");
+        let shim = Path::new("/cmd/python3");
+        let mut options = Options::default();
+        assert!(check_recursion(exe, shim, 15, &options).is_ok());
+        match check_recursion(exe, shim, 16, &options) {
+            Err(Error::TooDeep(16)) => {},
+            r => panic!("unexpected {:?}", r),
+        }
+        options.set_max_depth(2);
+        assert!(check_recursion(exe, shim, 1, &options).is_ok());
+        assert!(check_recursion(exe, shim, 2, &options).is_err());
+    }
+
+    // Run a shell script writing to the file at $0, and read it back.
+    #[cfg(unix)]
+    fn child_output(command: &mut Command, name: &str, script: &str)
+            -> String {
+        let dir = TempDir::new(&format!("procs-{}", name));
+        let out = dir.join("out");
+        command.arg("-c");
+        command.arg(script);
+        command.arg(&out);
+        let options = Options::default();
+        let code = run(Path::new("/bin/sh"), command, false, &options);
+        assert_eq!(code.unwrap(), 0);
+        fs::read_to_string(&out).unwrap()
+    }
+
+    // Run a helper dumping its environment, and read it back.
+    #[cfg(unix)]
+    fn child_env(command: &mut Command, name: &str)
+            -> Vec<(String, String)> {
+        let content = child_output(command, name, "env > \"$0\"");
+        content.lines().filter_map(|line| line.split_once('='))
+            .map(|(k, v)| (String::from(k), String::from(v)))
+            .collect()
+    }
+
+    #[cfg(unix)]
+    fn get<'a>(vars: &'a [(String, String)], name: &str) -> Option<&'a str> {
+        vars.iter().find(|&(k, _)| k == name).map(|(_, v)| v.as_str())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn env_ops() {
+        let mut command = Command::new();
+        command.arg("/bin/sh");
+        command.push_env(EnvOp::Set("PYTHONUTF8".into(), "1".into()));
+        command.push_env(EnvOp::Set("PYTHONPATH".into(), "/x".into()));
+        command.push_env(EnvOp::Unset("PYTHONPATH".into()));
+        command.push_env(EnvOp::PrependPath("PATH".into(), "/opt/a".into()));
+        command.push_env(EnvOp::PrependPath("PATH".into(), "/opt/b".into()));
+        command.push_env(EnvOp::PrependPath(
+            "PYTHONUP_SHIM_TEST_UNSET".into(), "/opt/c".into(),
+        ));
+
+        let vars = child_env(&mut command, "env-ops");
+        let path = env::var("PATH").unwrap();
+        assert_eq!(get(&vars, "PYTHONUTF8"), Some("1"));
+        assert_eq!(get(&vars, "PYTHONPATH"), None);
+        assert_eq!(
+            get(&vars, "PATH"),
+            Some(format!("/opt/b:/opt/a:{}", path).as_str()),
+        );
+        assert_eq!(get(&vars, "PYTHONUP_SHIM_TEST_UNSET"), Some("/opt/c"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn depth_passed_on() {
+        use super::{depth, DEPTH_ENV};
+
+        let mut command = Command::new();
+        command.arg("/bin/sh");
+        let vars = child_env(&mut command, "depth");
+        let expected = (depth() + 1).to_string();
+        assert_eq!(get(&vars, DEPTH_ENV), Some(expected.as_str()));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn env_inherited() {
+        let mut command = Command::new();
+        command.arg("/bin/sh");
+        let vars = child_env(&mut command, "env-inherited");
+        assert_eq!(get(&vars, "HOME"), env::var("HOME").ok().as_deref());
+    }
+
+    #[test]
+    fn child_var_names() {
+        use std::ffi::OsStr;
+        use std::process;
+        use super::child_var;
+
+        let mut cmd = process::Command::new("python");
+        cmd.env("PYTHONUP_SHIM_TEST_CASE", "1");
+        let name = OsStr::new("pythonup_shim_test_case");
+        let expected = if cfg!(windows) { Some("1".into()) } else { None };
+        assert_eq!(child_var(&cmd, name), expected);
+        let name = OsStr::new("PYTHONUP_SHIM_TEST_CASE");
+        assert_eq!(child_var(&cmd, name), Some("1".into()));
+    }
+
+    #[test]
+    fn installation_first() {
+        let root = TempDir::new("procs-path");
+        let install = root.join("3.7");
+        let scripts = root.mkdir("3.7/Scripts");
+        let cmd = root.mkdir("cmd");
+        let shims = vec![cmd.clone(), root.join("Scripts")];
+        let other = env::temp_dir();
+
+        // Shim directories are found however they are written.
+        let old = env::join_paths([
+            cmd.join(""), other.clone(), root.join("3.7/../Scripts/."),
+            root.join("./cmd"),
+        ]).ok();
+        let path = |exe: &Path| {
+            let path = installation_path(exe, &shims, old.clone());
+            env::split_paths(&path.unwrap()).collect::<Vec<_>>()
+        };
+        let expected = vec![install.clone(), scripts.clone(), other.clone()];
+        assert_eq!(path(&install.join("python.exe")), expected);
+        assert_eq!(path(&scripts.join("pip.exe")), expected);
+        assert_eq!(path(Path::new("python.exe")), vec![other.clone()]);
+    }
+
+    #[test]
+    fn dir_keys() {
+        use super::dir_key;
+
+        let key = |s: &str| dir_key(Path::new(s));
+        assert_eq!(key("/py/cmd/"), key("/py/./cmd"));
+        assert_eq!(key("/py/cmd"), key("/py/Scripts/../cmd"));
+        assert_eq!(key("../cmd"), PathBuf::from("../cmd"));
+        assert_eq!(key("C:\\PY\\CMD") == key("c:\\py\\cmd"), cfg!(windows));
+    }
+
+    #[test]
+    fn configured_shim_dirs() {
+        use super::shim_dirs;
+
+        let root = TempDir::new("procs-shim-dirs");
+        let installation = root.write(
+            "lib/installation.json",
+            r#"{"cmd_dir": "../cmd", "scripts_dir": "../Scripts"}"#,
+        );
+        let mut options = Options::default();
+        assert_eq!(shim_dirs(&options).len(), 1);
+        options.set_installation(&installation);
+        assert_eq!(&shim_dirs(&options)[1..], &[
+            root.join("lib/../cmd"), root.join("lib/../Scripts"),
+        ]);
+    }
+
+    #[test]
+    fn bad_working_dir() {
+        let exe = env::current_exe().unwrap();
+        for dir in &[exe.with_file_name("nonexistent"), exe] {
+            match check_working_dir(dir) {
+                Err(e @ Error::WorkingDir(_, _)) => {
+                    assert_eq!(e.exit_code(), 126);
+                },
+                r => panic!("unexpected {:?}", r),
+            }
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn run_in_working_dir() {
+        let dir = TempDir::new("procs-cwd-dir");
+
+        let mut command = Command::new();
+        command.arg("/bin/sh");
+        command.set_working_dir(dir.path());
+        let pwd = child_output(&mut command, "cwd", "pwd -P > \"$0\"");
+        assert_eq!(
+            Path::new(pwd.trim_end()),
+            fs::canonicalize(dir.path()).unwrap(),
+        );
+    }
+
+    // Stub executables logging their names when run, in a directory unique
+    // to each test.
+    #[cfg(unix)]
+    struct Stubs(TempDir);
+
+    #[cfg(unix)]
+    impl Stubs {
+        fn new(name: &str) -> Self {
+            Stubs(TempDir::new(&format!("procs-stubs-{}", name)))
+        }
+
+        // A command running a stub exiting with the code.
+        fn command(&self, name: &str, code: i32, when: Condition)
+                -> Command {
+            let script = format!("{}\nexit {}", self.logger(name), code);
+            self.script(name, &script, when)
+        }
+
+        // Shell command to log the name.
+        fn logger(&self, name: &str) -> String {
+            format!("echo {} >> '{}'", name, self.0.join("log").display())
+        }
+
+        // A command running a stub with the script.
+        fn script(&self, name: &str, script: &str, when: Condition)
+                -> Command {
+            use std::os::unix::fs::PermissionsExt;
+
+            let exe = self.0.join(name);
+            fs::write(&exe, format!("#!/bin/sh\n{}\n", script)).unwrap();
+            fs::set_permissions(&exe, fs::Permissions::from_mode(0o755))
+                .unwrap();
+
+            let mut command = Command::new();
+            command.arg(exe);
+            command.set_condition(when);
+            // Do not pass on arguments of the test runner.
+            command.set_appends_own_args(false);
+            command
+        }
+
+        fn run(&self, commands: Vec<Command>, report: Report)
+                -> (i32, Vec<String>) {
+            let mut options = Options::default();
+            options.set_report(report);
+            self.run_with(commands, options)
+        }
+
+        fn run_with(&self, commands: Vec<Command>, options: Options)
+                -> (i32, Vec<String>) {
+            let code = run_all(Commands::new(commands, options)).unwrap();
+            let log = fs::read_to_string(self.0.join("log"))
+                .unwrap_or_default();
+            fs::remove_file(self.0.join("log")).unwrap_or_default();
+            (code, log.lines().map(String::from).collect())
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn stop_on_failure() {
+        // The default, same as shims before conditions are configurable.
+        let stubs = Stubs::new("stop");
+        let commands = vec![
+            stubs.command("pip", 0, Condition::OnSuccess),
+            stubs.command("fail", 3, Condition::OnSuccess),
+            stubs.command("relink", 0, Condition::OnSuccess),
+        ];
+        let (code, log) = stubs.run(commands, Report::Last);
+        assert_eq!(code, 3);
+        assert_eq!(log, vec!["pip", "fail"]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn always() {
+        let stubs = Stubs::new("always");
+        let commands = || vec![
+            stubs.command("pip", 1, Condition::OnSuccess),
+            stubs.command("relink", 0, Condition::Always),
+        ];
+        let (code, log) = stubs.run(commands(), Report::First);
+        assert_eq!(code, 1);
+        assert_eq!(log, vec!["pip", "relink"]);
+        assert_eq!(stubs.run(commands(), Report::Last).0, 0);
+        assert_eq!(stubs.run(commands(), Report::Worst).0, 1);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn on_failure() {
+        let stubs = Stubs::new("on-failure");
+        let commands = vec![
+            stubs.command("a", 2, Condition::OnSuccess),
+            stubs.command("cleanup", 0, Condition::OnFailure),
+            stubs.command("b", 0, Condition::OnSuccess),
+            stubs.command("unneeded", 0, Condition::OnFailure),
+        ];
+        let (code, log) = stubs.run(commands, Report::Worst);
+        assert_eq!(code, 2);
+        assert_eq!(log, vec!["a", "cleanup", "b"]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn worst() {
+        let stubs = Stubs::new("worst");
+        let commands = vec![
+            stubs.command("a", 1, Condition::Always),
+            stubs.command("b", 5, Condition::Always),
+            stubs.command("c", 0, Condition::Always),
+        ];
+        let (code, log) = stubs.run(commands, Report::Worst);
+        assert_eq!(code, 5);
+        assert_eq!(log, vec!["a", "b", "c"]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn nothing_run() {
+        let stubs = Stubs::new("nothing");
+        let commands = vec![stubs.command("a", 0, Condition::OnFailure)];
+        assert_eq!(stubs.run(commands, Report::First), (0, vec![]));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn detached_queue() {
+        use super::{
+            pending_path, queue, run_detached, Launcher, PlatformLauncher};
+
+        // The stub queues itself once more when first run, like a shim run
+        // meanwhile would.
+        let stubs = Stubs::new("detached");
+        let lock = stubs.0.join("lock");
+        let rerun = stubs.0.join("rerun");
+        let script = format!(
+            "{}\n[ -e '{}' ] || {{ touch '{1}'; touch '{}'; }}",
+            stubs.logger("relink"), rerun.display(),
+            pending_path(&lock).display(),
+        );
+        let relink = stubs.script("relink", &script, Condition::Always);
+        let args = || vec![relink.exe().unwrap().clone().into_os_string()];
+        let log = || fs::read_to_string(stubs.0.join("log"))
+            .unwrap_or_default();
+
+        // Left queued for the holder of the lock to run.
+        let file = PlatformLauncher.try_lock(&lock).unwrap().unwrap();
+        queue(&lock).unwrap();
+        run_detached(&lock, args()).unwrap();
+        assert_eq!(log(), "");
+        assert!(pending_path(&lock).exists());
+        drop(file);
+
+        run_detached(&lock, args()).unwrap();
+        assert_eq!(log(), "relink\nrelink\n");
+        assert!(!pending_path(&lock).exists());
+
+        // Nothing to run unless queued.
+        run_detached(&lock, args()).unwrap();
+        assert_eq!(log(), "relink\nrelink\n");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn watch_dir() {
+        let stubs = Stubs::new("watch");
+        let scripts = stubs.0.join("scripts");
+        fs::create_dir(&scripts).unwrap();
+        fs::write(scripts.join("pip"), b"").unwrap();
+
+        let run = |script: &str, watch_dir: &Path| {
+            let script = format!("{}\n{}", stubs.logger("pip"), script);
+            let commands = vec![
+                stubs.script("pip", &script, Condition::OnSuccess),
+                stubs.command("relink", 0, Condition::OnSuccess),
+            ];
+            let mut options = Options::default();
+            options.set_watch_dir(watch_dir);
+            stubs.run_with(commands, options).1
+        };
+        let ran = vec!["pip", "relink"];
+
+        assert_eq!(run("true", &scripts), vec!["pip"]);
+        let read = format!("cat '{}'", scripts.join("pip").display());
+        assert_eq!(run(&read, &scripts), vec!["pip"]);
+        let add = format!("touch '{}'", scripts.join("black").display());
+        assert_eq!(run(&add, &scripts), ran);
+        let change = format!("echo 1 > '{}'", scripts.join("pip").display());
+        assert_eq!(run(&change, &scripts), ran);
+        let remove = format!("rm '{}'", scripts.join("black").display());
+        assert_eq!(run(&remove, &scripts), ran);
+        assert_eq!(run("true", &stubs.0.join("nonexistent")), ran);
+    }
+
+    #[test]
+    fn lock_paths() {
+        let lock = |args: &[&str]| {
+            let mut cmd = ::std::process::Command::new("python");
+            cmd.args(args);
+            lock_path(&cmd)
+        };
+        assert_eq!(lock(&["-m", "pythonup"]), lock(&["-m", "pythonup"]));
+        assert_ne!(lock(&["-m", "pythonup"]), lock(&["-m", "pythonup", "-"]));
+        assert_ne!(lock(&["-m", "pythonup"]), lock(&["-mpythonup"]));
+        assert_eq!(lock(&[]).parent(), Some(env::temp_dir().as_path()));
+    }
+
+    #[test]
+    fn run_empty() {
+        let commands = Commands::new(vec![], Options::default());
+        assert_eq!(run_all(commands).unwrap_err().exit_code(), 124);
     }
 }
@@ -0,0 +1,255 @@
+//! Unix launcher.
+//!
+//! When launched, the child is spawned and the shim stays around as its
+//! parent. Termination signals sent to the shim are forwarded to the child,
+//! and on Linux the child is killed if the shim dies before it. Signals
+//! received while no child runs, e.g. between commands, terminate the shim.
+//!
+//! The shim can also be replaced by the child entirely with `exec`, so the
+//! child gets signals, exit codes, and its place in the process tree as if
+//! it is run directly.
+//!
+//! Detached children run in a session of their own. Lock files are locked
+//! with `flock`, so they are released when closed, even if the process is
+//! killed.
+
+extern crate libc;
+
+use std::{io, mem, ptr};
+use std::fs::{File, OpenOptions};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::os::unix::process::{CommandExt, ExitStatusExt};
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicI32, Ordering};
+
+use self::libc::{c_int, c_void, siginfo_t, SIGHUP, SIGINT, SIGTERM};
+
+use errors::Error;
+use super::Launcher;
+
+// Signals forwarded from the shim to the child.
+const FORWARDED_SIGNALS: [c_int; 3] = [SIGINT, SIGTERM, SIGHUP];
+
+static PID: AtomicI32 = AtomicI32::new(0);
+
+fn forward(signum: c_int) {
+    let pid = PID.load(Ordering::SeqCst);
+    if pid != 0 {
+        unsafe { libc::kill(pid, signum) };
+    }
+}
+
+extern "C" fn handle_signal(
+        signum: c_int, info: *mut siginfo_t, _: *mut c_void) {
+    if PID.load(Ordering::SeqCst) == 0 {
+        // Without a child, act as if the signal is not handled. It is
+        // blocked until the handler returns, and delivered again then.
+        unsafe {
+            libc::signal(signum, libc::SIG_DFL);
+            libc::raise(signum);
+        }
+        return;
+    }
+    // Signals generated by the kernel, e.g. CTRL-C from the terminal, are
+    // already delivered to the whole foreground process group, including the
+    // child. Only pass on those sent to the shim specifically.
+    if info.is_null() || unsafe { (*info).si_code } <= 0 {
+        forward(signum);
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn tie_to_parent(cmd: &mut Command) {
+    let parent = unsafe { libc::getpid() };
+    let hook = move || {
+        let ok = unsafe { libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGKILL) };
+        if ok != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // The parent may have died before the signal was set up.
+        if unsafe { libc::getppid() } != parent {
+            unsafe { libc::raise(libc::SIGKILL) };
+        }
+        Ok(())
+    };
+    unsafe { cmd.pre_exec(hook) };
+}
+
+#[cfg(not(target_os = "linux"))]
+fn tie_to_parent(_: &mut Command) {}
+
+/// Launcher keeping the shim as the child's parent.
+pub struct UnixLauncher;
+
+impl Launcher for UnixLauncher {
+    fn setup(&self) -> Result<(), Error> {
+        for &signum in &FORWARDED_SIGNALS {
+            let ok = unsafe {
+                let mut action: libc::sigaction = mem::zeroed();
+                action.sa_sigaction = handle_signal as *const () as usize;
+                action.sa_flags = libc::SA_SIGINFO | libc::SA_RESTART;
+                libc::sigemptyset(&mut action.sa_mask);
+                libc::sigaction(signum, &action, ptr::null_mut())
+            };
+            if ok != 0 {
+                return Err(Error::Setup("signal handler set error"));
+            }
+        }
+        Ok(())
+    }
+
+    fn launch(&self, cmd: &mut Command) -> Result<i32, Error> {
+        tie_to_parent(cmd);
+
+        let mut child = cmd.spawn().map_err(|e| {
+            Error::Spawn(PathBuf::from(cmd.get_program()), e)
+        })?;
+        PID.store(child.id() as i32, Ordering::SeqCst);
+
+        let result = child.wait();
+        PID.store(0, Ordering::SeqCst);
+        let result = result.map_err(Error::Wait)?;
+
+        // Follow the shell convention for children killed by a signal.
+        Ok(match result.code() {
+            Some(code) => code,
+            None => 128 + result.signal().unwrap_or(libc::SIGKILL),
+        })
+    }
+
+    fn exec(&self, cmd: &mut Command) -> Result<i32, Error> {
+        let e = cmd.exec();
+        Err(Error::Spawn(PathBuf::from(cmd.get_program()), e))
+    }
+
+    fn detach(&self, cmd: &mut Command) -> Result<(), Error> {
+        let hook = || {
+            if unsafe { libc::setsid() } == -1 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        };
+        unsafe { cmd.pre_exec(hook) };
+        cmd.stdin(Stdio::null()).stdout(Stdio::null()).stderr(Stdio::null());
+
+        cmd.spawn().map_err(|e| {
+            Error::Spawn(PathBuf::from(cmd.get_program()), e)
+        })?;
+        Ok(())
+    }
+
+    fn try_lock(&self, path: &Path) -> Result<Option<File>, Error> {
+        let lock_error = |e| Error::Lock(path.to_path_buf(), e);
+        let file = OpenOptions::new().write(true).create(true)
+            .truncate(false).open(path)
+            .map_err(lock_error)?;
+        let fd = file.as_raw_fd();
+        if unsafe { libc::flock(fd, libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+            let e = io::Error::last_os_error();
+            if e.raw_os_error() == Some(libc::EWOULDBLOCK) {
+                return Ok(None);
+            }
+            return Err(lock_error(e));
+        }
+        Ok(Some(file))
+    }
+}
+
+
+#[cfg(test)]
+mod unix_launcher_test {
+    use std::env;
+    use std::fs;
+    use std::os::unix::process::ExitStatusExt;
+    use std::process::Command;
+    use std::sync::Mutex;
+    use std::sync::atomic::Ordering;
+    use std::thread;
+    use std::time::Duration;
+    use super::{forward, libc, Launcher, UnixLauncher, PID, SIGTERM};
+
+    // The child's PID is global, so only launch one child at a time.
+    static LOCK: Mutex<()> = Mutex::new(());
+
+    fn sh(script: &str) -> Command {
+        let mut cmd = Command::new("/bin/sh");
+        cmd.arg("-c").arg(script);
+        cmd
+    }
+
+    #[test]
+    fn exit_code() {
+        let _lock = LOCK.lock().unwrap();
+        let code = UnixLauncher.launch(&mut sh("exit 3")).unwrap();
+        assert_eq!(code, 3);
+    }
+
+    #[test]
+    fn killed() {
+        let _lock = LOCK.lock().unwrap();
+        let code = UnixLauncher.launch(&mut sh("kill -TERM $$")).unwrap();
+        assert_eq!(code, 128 + SIGTERM);
+    }
+
+    #[test]
+    fn spawn_failure() {
+        let _lock = LOCK.lock().unwrap();
+        let mut cmd = Command::new("/nonexistent/python");
+        let e = UnixLauncher.launch(&mut cmd).unwrap_err();
+        assert_eq!(e.exit_code(), 127);
+        assert_eq!(PID.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn exec_failure() {
+        // The process is only replaced on success, so this is safe to test.
+        let mut cmd = Command::new("/nonexistent/python");
+        let e = UnixLauncher.exec(&mut cmd).unwrap_err();
+        assert_eq!(e.exit_code(), 127);
+    }
+
+    #[test]
+    fn forward_signal() {
+        let _lock = LOCK.lock().unwrap();
+        let ready = env::temp_dir().join(format!(
+            "pythonup-shim-forward-{}", ::std::process::id(),
+        ));
+        let _ = fs::remove_file(&ready);
+
+        let script = format!(
+            "trap 'kill $!; exit 7' TERM; touch '{}'; sleep 10 & wait",
+            ready.display(),
+        );
+        let handle = thread::spawn(move || {
+            UnixLauncher.launch(&mut sh(&script)).unwrap()
+        });
+        while !ready.exists() {
+            thread::sleep(Duration::from_millis(10));
+        }
+        forward(SIGTERM);
+
+        assert_eq!(handle.join().unwrap(), 7);
+        fs::remove_file(&ready).unwrap();
+    }
+
+    #[test]
+    fn terminated_without_child() {
+        // Handlers are process-wide, so they are set up in a test process
+        // of their own.
+        if env::var_os("SHIM_TEST_RAISE").is_some() {
+            UnixLauncher.setup().unwrap();
+            unsafe { libc::raise(SIGTERM) };
+            thread::sleep(Duration::from_secs(10));
+            return;
+        }
+        // Test names are relative to the crate.
+        let name = concat!(module_path!(), "::terminated_without_child");
+        let name = name.split_once("::").unwrap().1;
+        let status = Command::new(env::current_exe().unwrap())
+            .args(["--exact", "--quiet", name])
+            .env("SHIM_TEST_RAISE", "1")
+            .status().unwrap();
+        assert_eq!(status.signal(), Some(SIGTERM));
+    }
+}
@@ -0,0 +1,151 @@
+//! Windows launcher.
+//!
+//! Most of this module, especially the part setting up the shild process, is
+//! based on how Pip creates an EXE launcher for console scripts, from
+//! [distlib], developed in C by Vinay Sajip.
+//!
+//! [distlib]: https://github.com/vsajip/distlib/blob/master/PC/launcher.c
+//!
+//! Detached children are left out of the job. Lock files are opened without
+//! sharing, so they are released when closed, even if the process is
+//! killed.
+
+extern crate winapi;
+
+use std::{mem, ptr};
+use std::fs::{File, OpenOptions};
+use std::os::windows::fs::OpenOptionsExt;
+use std::os::windows::io::AsRawHandle;
+use std::os::windows::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+
+use self::winapi::ctypes::c_void;
+use self::winapi::shared::minwindef::{BOOL, DWORD, LPVOID, TRUE};
+use self::winapi::shared::winerror::{
+    ERROR_ACCESS_DENIED, ERROR_SHARING_VIOLATION};
+use self::winapi::um::consoleapi::SetConsoleCtrlHandler;
+use self::winapi::um::jobapi2::{
+    AssignProcessToJobObject, CreateJobObjectW, QueryInformationJobObject,
+    SetInformationJobObject};
+use self::winapi::um::winbase::{
+    CREATE_BREAKAWAY_FROM_JOB, CREATE_NEW_PROCESS_GROUP, DETACHED_PROCESS};
+use self::winapi::um::wincon::{CTRL_C_EVENT, GenerateConsoleCtrlEvent};
+use self::winapi::um::winnt::{
+    JOBOBJECT_EXTENDED_LIMIT_INFORMATION,
+    JOB_OBJECT_LIMIT_KILL_ON_JOB_CLOSE, JOB_OBJECT_LIMIT_SILENT_BREAKAWAY_OK,
+    JobObjectExtendedLimitInformation};
+
+use errors::Error;
+use super::Launcher;
+
+static mut PID: u32 = 0;
+
+unsafe extern "system" fn handle_ctrl(etype: DWORD) -> BOOL {
+    if etype == CTRL_C_EVENT && PID != 0 {
+        // FIXME: Why does this work? The two arguments of
+        // GenerateConsoleCtrlEvent are dwCtrlEvent and dwProcessGroupId, so
+        // we're passing them backwards... But this is what what Python's
+        // launchers do, and IT ACTUALLY WORKS. I'm letting it stand for now.
+        GenerateConsoleCtrlEvent(PID, CTRL_C_EVENT);
+    }
+    TRUE
+}
+
+unsafe fn setup_child(child: &mut Child) -> Result<(), &'static str> {
+    PID = child.id();
+
+    let job = CreateJobObjectW(ptr::null_mut(), ptr::null());
+    let mut info: JOBOBJECT_EXTENDED_LIMIT_INFORMATION = mem::zeroed();
+
+    let mut ok;
+
+    ok = QueryInformationJobObject(
+        job, JobObjectExtendedLimitInformation,
+        &mut info as *mut _ as LPVOID,
+        mem::size_of_val(&info) as DWORD,
+        ptr::null_mut(),
+    );
+    if ok != TRUE {
+        return Err("job information query error");
+    }
+
+    info.BasicLimitInformation.LimitFlags =
+        info.BasicLimitInformation.LimitFlags |
+        JOB_OBJECT_LIMIT_KILL_ON_JOB_CLOSE |
+        JOB_OBJECT_LIMIT_SILENT_BREAKAWAY_OK;
+    ok = SetInformationJobObject(
+        job, JobObjectExtendedLimitInformation,
+        &mut info as *mut _ as LPVOID,
+        mem::size_of_val(&info) as DWORD,
+    );
+    if ok != TRUE {
+        return Err("job information set error");
+    }
+
+    AssignProcessToJobObject(job, child.as_raw_handle() as *mut c_void);
+
+    Ok(())
+}
+
+/// Launcher using a job object to tie the child's lifetime to the shim.
+pub struct WindowsLauncher;
+
+impl Launcher for WindowsLauncher {
+    /// Set up the parent process.
+    ///
+    /// This sets up the CTRL handler so the parent passes on the CTRL-C event
+    /// to its child.
+    fn setup(&self) -> Result<(), Error> {
+        let ok = unsafe { SetConsoleCtrlHandler(Some(handle_ctrl), TRUE) };
+        if ok == TRUE {
+            Ok(())
+        } else {
+            Err(Error::Setup("control handler set error"))
+        }
+    }
+
+    fn launch(&self, cmd: &mut Command) -> Result<i32, Error> {
+        let mut child = cmd.spawn().map_err(|e| {
+            Error::Spawn(PathBuf::from(cmd.get_program()), e)
+        })?;
+
+        unsafe { setup_child(&mut child).map_err(Error::Setup)? };
+
+        let result = child.wait().map_err(Error::Wait)?;
+
+        // Doc seems to suggest this won't happen on Windows, but I'm not sure.
+        // 137 is a common value seen with SIGKILL terminated programs.
+        Ok(result.code().unwrap_or(137))
+    }
+
+    fn detach(&self, cmd: &mut Command) -> Result<(), Error> {
+        cmd.stdin(Stdio::null()).stdout(Stdio::null()).stderr(Stdio::null());
+        let flags = DETACHED_PROCESS | CREATE_NEW_PROCESS_GROUP;
+        cmd.creation_flags(flags | CREATE_BREAKAWAY_FROM_JOB);
+        let mut result = cmd.spawn();
+
+        // The shim may be in a job not allowing to break away from it, e.g.
+        // of another shim. Stay in it then.
+        let denied = Some(ERROR_ACCESS_DENIED as i32);
+        if result.as_ref().err().and_then(|e| e.raw_os_error()) == denied {
+            result = cmd.creation_flags(flags).spawn();
+        }
+        result.map_err(|e| {
+            Error::Spawn(PathBuf::from(cmd.get_program()), e)
+        })?;
+        Ok(())
+    }
+
+    fn try_lock(&self, path: &Path) -> Result<Option<File>, Error> {
+        let opened = OpenOptions::new().write(true).create(true)
+            .truncate(false).share_mode(0).open(path);
+        match opened {
+            Ok(file) => Ok(Some(file)),
+            Err(ref e)
+                    if e.raw_os_error()
+                        == Some(ERROR_SHARING_VIOLATION as i32) => Ok(None),
+            Err(e) => Err(Error::Lock(path.to_path_buf(), e)),
+        }
+    }
+}
@@ -0,0 +1,538 @@
+//! Link scripts of active versions into pythonup's scripts directory.
+//!
+//! This is a native port of `activate` in `pythonup.operations.link`, so
+//! shims for pip can refresh links without starting an interpreter. Scripts
+//! in each version's `Scripts` directory are copied, except unqualified pip
+//! and easy_install, and those pythonup already provides. Major version pip
+//! and easy_install commands get shims, relinking after they run, and so
+//! does each version's major Python command. Anything else in the scripts
+//! directory is stale, and removed.
+
+use std::collections::HashSet;
+use std::ffi::OsStr;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use cmds::{Command, Options};
+use digest::sha256_file;
+use writer;
+
+/// A command running the executable, with its digest to be verified.
+///
+/// The path is escaped, so it is run as-is even if it looks like it
+/// contains placeholders.
+pub fn exe_command(exe: &Path) -> Command {
+    let mut command = Command::new();
+    command.arg(writer::escape(exe));
+    if let Ok(digest) = sha256_file(exe) {
+        command.set_exe_digest(digest);
+    }
+    command
+}
+
+/// What to do if a target exists.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Overwrite {
+    /// Always write the target.
+    #[default]
+    Yes,
+
+    /// Only write missing targets.
+    No,
+
+    /// Only write targets with different content.
+    Smart,
+}
+
+/// An installed version to link scripts from.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Version {
+    pub name: String,
+    pub dir: PathBuf,
+}
+
+impl Version {
+    pub fn new<S: Into<String>, P: Into<PathBuf>>(name: S, dir: P) -> Self {
+        Version { name: name.into(), dir: dir.into() }
+    }
+
+    // Name without the architecture, e.g. `3.7` for `3.7-32`.
+    fn arch_free_name(&self) -> &str {
+        self.name.split('-').next().unwrap_or_default()
+    }
+
+    fn major(&self) -> &str {
+        self.name.split(['.', '-']).next().unwrap_or_default()
+    }
+}
+
+// The series of a command named after a major version, e.g. `3` for
+// `pip3.exe`.
+fn major_series(path: &Path) -> Option<&str> {
+    let stem = path.file_stem()?.to_str()?;
+    let series = stem.strip_prefix("pip")
+        .or_else(|| stem.strip_prefix("python"))?;
+    if !series.is_empty() && series.bytes().all(|b| b.is_ascii_digit()) {
+        Some(series)
+    } else {
+        None
+    }
+}
+
+fn has_stem(path: &Path, stems: &[String]) -> bool {
+    let stem = path.file_stem().and_then(OsStr::to_str);
+    stems.iter().any(|s| Some(s.as_str()) == stem)
+}
+
+/// Find scripts of versions to copy, and to write shims for.
+///
+/// A script is taken from the first version providing it, so earlier
+/// versions take precedence.
+pub fn collect_scripts(versions: &[Version]) -> (Vec<PathBuf>, Vec<PathBuf>) {
+    let mut names = HashSet::new();
+    let mut scripts = vec![];
+    let mut shims = vec![];
+    for version in versions {
+        let entries = match fs::read_dir(version.dir.join("Scripts")) {
+            Ok(entries) => entries,
+            Err(_) => { continue; },
+        };
+        let mut paths: Vec<_> = entries.filter_map(Result::ok)
+            .map(|entry| entry.path())
+            .collect();
+        paths.sort();
+
+        let blacklisted = [
+            // Encourage people to always use qualified commands.
+            String::from("easy_install"), String::from("pip"),
+            // Fully qualified pip is already populated on installation.
+            format!("pip{}", version.arch_free_name()),
+        ];
+        let shimmed = [
+            format!("pip{}", version.major()),
+            format!("easy_install-{}", version.arch_free_name()),
+        ];
+        for path in paths {
+            let name = path.file_name().unwrap_or_default().to_os_string();
+            if names.contains(&name) || has_stem(&path, &blacklisted) {
+                continue;
+            }
+            names.insert(name);
+            if has_stem(&path, &shimmed) {
+                shims.push(path);
+            } else {
+                scripts.push(path);
+            }
+        }
+    }
+    (scripts, shims)
+}
+
+/// Changes made to the scripts directory.
+#[derive(Debug, Default)]
+pub struct Changes {
+    /// Targets written.
+    pub published: Vec<PathBuf>,
+
+    /// Stale targets removed.
+    pub removed: Vec<PathBuf>,
+
+    /// Targets failed to be written or removed, with the reason.
+    pub failed: Vec<(PathBuf, io::Error)>,
+}
+
+fn same_content(a: &Path, b: &Path) -> bool {
+    match (fs::read(a), fs::read(b)) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => false,
+    }
+}
+
+/// Writer of links into a scripts directory.
+#[derive(Debug)]
+pub struct Linker {
+    scripts_dir: PathBuf,
+    base: PathBuf,
+    relink: Option<Command>,
+    overwrite: Overwrite,
+    installation: Option<PathBuf>,
+    key: Option<Vec<u8>>,
+}
+
+impl Linker {
+    /// Create a linker writing into `scripts_dir`, with shims written from
+    /// the `base` executable.
+    pub fn new<P, Q>(scripts_dir: P, base: Q) -> Self
+            where P: Into<PathBuf>, Q: Into<PathBuf> {
+        Linker {
+            scripts_dir: scripts_dir.into(),
+            base: base.into(),
+            relink: None,
+            overwrite: Overwrite::default(),
+            installation: None,
+            key: None,
+        }
+    }
+
+    /// Command run by shims for pip after it, to relink.
+    ///
+    /// It only runs if pip changes the `Scripts` directory it belongs to.
+    pub fn set_relink(&mut self, command: Command) {
+        self.relink = Some(command);
+    }
+
+    pub fn set_overwrite(&mut self, overwrite: Overwrite) {
+        self.overwrite = overwrite;
+    }
+
+    /// `installation.json` recorded by shims, to find pythonup's
+    /// directories with.
+    ///
+    /// Shims named after a major version, e.g. `pip3.exe`, then run it from
+    /// the first active version in the series when launched, so they need
+    /// not be written again when active versions change.
+    pub fn set_installation<P: Into<PathBuf>>(&mut self, path: P) {
+        self.installation = Some(path.into());
+    }
+
+    /// Key to sign shims with, so they run for a user with it.
+    pub fn set_key(&mut self, key: Vec<u8>) {
+        self.key = Some(key);
+    }
+
+    fn options(&self) -> Options {
+        let mut options = Options::default();
+        if let Some(ref installation) = self.installation {
+            options.set_installation(writer::escape(installation));
+        }
+        options
+    }
+
+    // Write the target as the overwrite policy allows. Failures are
+    // recorded, so other targets are still written.
+    fn publish<S, W>(&self, target: &Path, same: S, write: W,
+                     changes: &mut Changes)
+            where S: FnOnce() -> bool, W: FnOnce() -> io::Result<()> {
+        let should_write = match self.overwrite {
+            Overwrite::Yes => true,
+            Overwrite::No => !target.exists(),
+            Overwrite::Smart => !target.exists() || !same(),
+        };
+        if !should_write {
+            return;
+        }
+        match write() {
+            Ok(()) => changes.published.push(target.to_path_buf()),
+            Err(e) => changes.failed.push((target.to_path_buf(), e)),
+        }
+    }
+
+    fn publish_shim(&self, target: &Path, commands: Vec<Command>,
+                    options: &Options, changes: &mut Changes) {
+        let content = fs::read(&self.base).and_then(|mut bytes| {
+            let key = self.key.as_ref().map(|key| &key[..]);
+            bytes.extend(writer::serialize_signed(&commands, options, key)?);
+            Ok(bytes)
+        });
+        let same = || match content {
+            Ok(ref content) => fs::read(target).ok().as_ref() == Some(content),
+            Err(_) => false,
+        };
+        let write = || match content {
+            Ok(ref content) => fs::write(target, content),
+            Err(ref e) => Err(io::Error::new(e.kind(), e.to_string())),
+        };
+        self.publish(target, same, write, changes);
+    }
+
+    /// Link scripts of the versions, and remove stale ones.
+    ///
+    /// Failures to write or remove a target are recorded in the changes. An
+    /// error is only returned if the scripts directory cannot be read.
+    pub fn link(&self, versions: &[Version]) -> io::Result<Changes> {
+        fs::create_dir_all(&self.scripts_dir)?;
+        let mut changes = Changes::default();
+        let mut using = HashSet::new();
+
+        let (scripts, shimmed) = collect_scripts(versions);
+        for source in scripts {
+            if !source.is_file() {
+                continue;
+            }
+            let target = self.scripts_dir.join(source.file_name().unwrap());
+            using.insert(target.clone());
+            let same = || same_content(&source, &target);
+            let write = || fs::copy(&source, &target).map(|_| ());
+            self.publish(&target, same, write, &mut changes);
+        }
+        for source in shimmed {
+            let target = self.scripts_dir.join(source.file_name().unwrap());
+            if !using.insert(target.clone()) {
+                continue;
+            }
+            let mut command = exe_command(&source);
+            if let Some(series) = major_series(&source) {
+                if self.installation.is_some() {
+                    let name = source.file_name().unwrap();
+                    command.set_resolve(Path::new("Scripts").join(name));
+                    command.set_series(series);
+                }
+            }
+            let mut commands = vec![command];
+            let mut options = self.options();
+            if let Some(ref relink) = self.relink {
+                commands.push(relink.clone());
+                let dir = source.parent().unwrap();
+                options.set_watch_dir(writer::escape(dir));
+            }
+            self.publish_shim(&target, commands, &options, &mut changes);
+        }
+        for version in versions {
+            let name = format!("python{}.exe", version.major());
+            let target = self.scripts_dir.join(name);
+            if !using.insert(target.clone()) {
+                continue;
+            }
+            let mut command = exe_command(&version.dir.join("python.exe"));
+            command.set_prepends_installation(true);
+            if self.installation.is_some() {
+                command.set_resolve("python.exe");
+                command.set_series(version.major());
+            }
+            let options = self.options();
+            self.publish_shim(&target, vec![command], &options, &mut changes);
+        }
+
+        for entry in fs::read_dir(&self.scripts_dir)? {
+            let path = entry?.path();
+            if using.contains(&path) {
+                continue;
+            }
+            match fs::remove_file(&path) {
+                Ok(()) => changes.removed.push(path),
+                Err(e) => changes.failed.push((path, e)),
+            }
+        }
+        Ok(changes)
+    }
+}
+
+
+#[cfg(test)]
+mod relink_test {
+    use std::fs;
+    use std::path::{Path, PathBuf};
+    use cmds::{Command, Commands, Placeholders};
+    use digest::sha256;
+    use super::{collect_scripts, major_series, Linker, Overwrite, Version};
+    use testing::TempDir;
+
+    // Two versions with overlapping scripts.
+    fn versions(dir: &TempDir) -> Vec<Version> {
+        for name in &[
+            "pip.exe", "pip3.exe", "pip3.7.exe", "easy_install.exe",
+            "easy_install-3.7.exe", "black.exe", "black-script.py",
+        ] {
+            dir.write(format!("3.7-32/Scripts/{}", name), "3.7");
+        }
+        for name in &["pip3.exe", "pip3.6.exe", "black.exe", "tox.exe"] {
+            dir.write(format!("3.6/Scripts/{}", name), "3.6");
+        }
+        vec![
+            Version::new("3.7-32", dir.join("3.7-32")),
+            Version::new("3.6", dir.join("3.6")),
+            Version::new("2.7", dir.join("2.7")),
+        ]
+    }
+
+    fn names(paths: &[PathBuf]) -> Vec<String> {
+        let mut names: Vec<_> = paths.iter()
+            .map(|p| p.file_name().unwrap().to_string_lossy().into_owned())
+            .collect();
+        names.sort();
+        names
+    }
+
+    fn commands(shim: &Path) -> Commands {
+        Commands::from_path(shim).unwrap()
+    }
+
+    #[test]
+    fn collect() {
+        let dir = TempDir::new("relink-collect");
+        let versions = versions(&dir);
+        let (scripts, shims) = collect_scripts(&versions);
+        assert_eq!(
+            names(&scripts),
+            vec!["black-script.py", "black.exe", "tox.exe"],
+        );
+        assert_eq!(names(&shims), vec!["easy_install-3.7.exe", "pip3.exe"]);
+
+        // The first version providing a script wins.
+        let black = scripts.iter().find(|p| p.ends_with("black.exe"));
+        assert!(black.unwrap().starts_with(dir.join("3.7-32")));
+        assert!(shims[1].starts_with(dir.join("3.7-32")));
+    }
+
+    #[test]
+    fn link() {
+        let dir = TempDir::new("relink-link");
+        let versions = versions(&dir);
+        let base = dir.write("shim.exe", "MZ");
+        let stale = dir.write("Scripts/flake8.exe", "");
+        let scripts_dir = &dir.join("Scripts");
+
+        let mut relink = Command::new();
+        relink.arg("shim-tool");
+        relink.arg("relink");
+        let mut linker = Linker::new(scripts_dir, &base);
+        linker.set_relink(relink.clone());
+
+        let changes = linker.link(&versions).unwrap();
+        assert_eq!(names(&changes.published), vec![
+            "black-script.py", "black.exe", "easy_install-3.7.exe",
+            "pip3.exe", "python2.exe", "python3.exe", "tox.exe",
+        ]);
+        assert_eq!(changes.removed, vec![stale]);
+        assert!(changes.failed.is_empty());
+
+        let black = fs::read_to_string(scripts_dir.join("black.exe"));
+        assert_eq!(black.unwrap(), "3.7");
+
+        let pip = commands(&scripts_dir.join("pip3.exe"));
+        let scripts = dir.join("3.7-32").join("Scripts");
+        assert_eq!(pip.options().watch_dir(), Some(&scripts));
+        let pip: Vec<_> = pip.collect();
+        assert_eq!(pip[0].exe(), Some(&scripts.join("pip3.exe")));
+        assert_eq!(pip[0].exe_digest(), Some(&sha256(b"3.7")));
+        assert_eq!(pip[1], relink);
+
+        // Only the first version with a major version gets a shim.
+        let python: Vec<_> = commands(&scripts_dir.join("python3.exe"))
+            .collect();
+        let exe = dir.join("3.7-32").join("python.exe");
+        assert_eq!(python.len(), 1);
+        assert_eq!(python[0].exe(), Some(&exe));
+        assert!(python[0].prepends_installation());
+        assert!(!pip[0].prepends_installation());
+    }
+
+    #[test]
+    fn dynamic() {
+        let dir = TempDir::new("relink-dynamic");
+        let versions = versions(&dir);
+        let base = dir.write("shim.exe", "MZ");
+        let scripts_dir = dir.join("Scripts");
+        let mut linker = Linker::new(&scripts_dir, &base);
+        linker.set_installation(dir.join("installation.json"));
+        linker.link(&versions).unwrap();
+
+        let command = |name: &str| {
+            commands(&scripts_dir.join(name)).next().unwrap()
+        };
+        let python = command("python3.exe");
+        assert_eq!(python.resolve(), Some(&PathBuf::from("python.exe")));
+        assert_eq!(python.series(), Some("3"));
+        let pip = command("pip3.exe");
+        let relative = Path::new("Scripts").join("pip3.exe");
+        assert_eq!(pip.resolve(), Some(&relative));
+        assert_eq!(pip.series(), Some("3"));
+        assert_eq!(command("easy_install-3.7.exe").resolve(), None);
+        assert_eq!(command("python2.exe").series(), Some("2"));
+        assert_eq!(major_series(Path::new("python3.7.exe")), None);
+    }
+
+    #[test]
+    fn literal_paths() {
+        let dir = TempDir::new("relink-%PATH%-${HOME}-{{x}}-$$");
+        let versions = versions(&dir);
+        let base = dir.write("shim.exe", "MZ");
+        let scripts_dir = dir.join("Scripts");
+        let mut linker = Linker::new(&scripts_dir, &base);
+        let mut relink = Command::new();
+        relink.arg("shim-tool");
+        linker.set_relink(relink);
+        linker.link(&versions).unwrap();
+
+        let expanded = |target: &Path| {
+            let commands = commands(target);
+            let p = Placeholders::for_shim(target, commands.options());
+            commands.expanded(&p)
+        };
+        let pip = expanded(&scripts_dir.join("pip3.exe"));
+        let scripts = dir.join("3.7-32").join("Scripts");
+        assert_eq!(pip.options().watch_dir(), Some(&scripts));
+        let pip: Vec<_> = pip.collect();
+        assert_eq!(pip[0].exe(), Some(&scripts.join("pip3.exe")));
+
+        let python: Vec<_> = expanded(&scripts_dir.join("python3.exe"))
+            .collect();
+        let exe = dir.join("3.7-32").join("python.exe");
+        assert_eq!(python[0].exe(), Some(&exe));
+    }
+
+    #[test]
+    fn overwrite() {
+        let dir = TempDir::new("relink-overwrite");
+        let versions = versions(&dir);
+        let base = dir.write("shim.exe", "MZ");
+        let scripts_dir = dir.join("Scripts");
+        let mut linker = Linker::new(&scripts_dir, &base);
+        linker.link(&versions).unwrap();
+
+        linker.set_overwrite(Overwrite::Smart);
+        let changes = linker.link(&versions).unwrap();
+        assert!(changes.published.is_empty());
+        assert!(changes.removed.is_empty());
+
+        dir.write("3.6/Scripts/tox.exe", "changed");
+        fs::remove_file(scripts_dir.join("python2.exe")).unwrap();
+        let changes = linker.link(&versions).unwrap();
+        assert_eq!(names(&changes.published), vec!["python2.exe", "tox.exe"]);
+
+        dir.write("3.6/Scripts/tox.exe", "changed again");
+        fs::remove_file(scripts_dir.join("python3.exe")).unwrap();
+        linker.set_overwrite(Overwrite::No);
+        let changes = linker.link(&versions).unwrap();
+        assert_eq!(names(&changes.published), vec!["python3.exe"]);
+
+        linker.set_overwrite(Overwrite::Yes);
+        let changes = linker.link(&versions).unwrap();
+        assert_eq!(changes.published.len(), 7);
+    }
+
+    #[test]
+    fn signed() {
+        let dir = TempDir::new("relink-signed");
+        let versions = versions(&dir);
+        let base = dir.write("shim.exe", "MZ");
+        let scripts_dir = dir.join("Scripts");
+        let installation = dir.join("{weird}/installation.json");
+        let mut linker = Linker::new(&scripts_dir, &base);
+        linker.set_installation(&installation);
+        linker.set_key(b"key".to_vec());
+        linker.link(&versions).unwrap();
+
+        let mut file = fs::File::open(scripts_dir.join("pip3.exe")).unwrap();
+        let key: Option<&[u8]> = Some(b"key");
+        let commands = Commands::from_signed_reader(&mut file, key).unwrap();
+        let placeholders = Placeholders::for_shim(&base, commands.options());
+        let commands = commands.expanded(&placeholders);
+        assert_eq!(commands.options().installation(), Some(&installation));
+    }
+
+    #[test]
+    fn missing_base() {
+        let dir = TempDir::new("relink-missing-base");
+        let versions = versions(&dir);
+        let linker = Linker::new(dir.join("Scripts"), dir.join("shim"));
+        let changes = linker.link(&versions).unwrap();
+        assert_eq!(names(&changes.published).len(), 3);
+        let failed: Vec<_> = changes.failed.into_iter().map(|(p, _)| p)
+            .collect();
+        assert_eq!(names(&failed), vec![
+            "easy_install-3.7.exe", "pip3.exe", "python2.exe", "python3.exe",
+        ]);
+    }
+}
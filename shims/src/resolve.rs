@@ -0,0 +1,448 @@
+//! Resolve executables against the version pinned for a directory.
+//!
+//! A version is pinned by a `.python-version` file in the working directory
+//! or any of its parents, like pyenv's local version. The `PYTHONUP_VERSION`
+//! environment variable takes precedence over files. Commands that opt in
+//! are run from the pinned version's installation, instead of the executable
+//! baked into the shim.
+//!
+//! If nothing is pinned, the shim may look for the first active version in
+//! pythonup's configuration instead, so `pythonup use` only needs to update
+//! the configuration, not every shim.
+
+extern crate serde_json;
+#[cfg(windows)] extern crate winapi;
+
+use std::env;
+use std::ffi::OsString;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use self::serde_json::Value;
+
+use cmds::{Command, Options};
+use errors::Error;
+
+pub const VERSION_FILE: &str = ".python-version";
+pub const VERSION_ENV: &str = "PYTHONUP_VERSION";
+
+// Pinning this means "do not pin", as in pyenv.
+const SYSTEM: &str = "system";
+
+/// Where a version is pinned.
+#[derive(Debug, PartialEq)]
+pub enum Source {
+    Env,
+    File(PathBuf),
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Source::Env => write!(f, "{}", VERSION_ENV),
+            Source::File(ref path) => write!(f, "{}", path.display()),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Pin {
+    pub name: String,
+    pub source: Source,
+}
+
+// The first line that is not blank or a comment.
+fn parse_version(content: &str) -> Option<&str> {
+    content.lines()
+        .map(str::trim)
+        .find(|line| !line.is_empty() && !line.starts_with('#'))
+}
+
+fn pin(name: &str, source: Source) -> Option<Pin> {
+    if name == SYSTEM {
+        return None;
+    }
+    Some(Pin { name: String::from(name), source })
+}
+
+/// Find the version pinned for `cwd`.
+///
+/// `env_value` is the value of `PYTHONUP_VERSION`, if set. An empty value is
+/// ignored. Files are looked up from `cwd` upwards, and the nearest wins,
+/// even if it pins nothing, as in pyenv.
+pub fn pinned_version(env_value: Option<OsString>, cwd: &Path)
+        -> Option<Pin> {
+    pinned_version_within(env_value, cwd, None)
+}
+
+// Like pinned_version, but files above `root` are not looked up.
+fn pinned_version_within(
+        env_value: Option<OsString>, cwd: &Path, root: Option<&Path>)
+        -> Option<Pin> {
+    if let Some(value) = env_value {
+        let value = value.to_string_lossy();
+        if !value.trim().is_empty() {
+            return pin(value.trim(), Source::Env);
+        }
+    }
+    for dir in cwd.ancestors() {
+        let path = dir.join(VERSION_FILE);
+        if path.is_file() {
+            let content = fs::read_to_string(&path).ok()?;
+            return parse_version(&content)
+                .and_then(|name| pin(name, Source::File(path.clone())));
+        }
+        if Some(dir) == root {
+            break;
+        }
+    }
+    None
+}
+
+/// Names an installation of the version may go by.
+///
+/// Installations are named by the minor version, e.g. `3.7` or `3.7-32`, but
+/// pyenv-style files often pin a patch version, e.g. `3.7.2`. The name is
+/// tried as-is first, and then without the patch version.
+pub fn candidate_names(name: &str) -> Vec<String> {
+    let mut names = vec![String::from(name)];
+    let (version, suffix) = match name.split_once('-') {
+        Some((version, suffix)) => (version, format!("-{}", suffix)),
+        None => (name, String::new()),
+    };
+    let parts: Vec<_> = version.split('.').collect();
+    if parts.len() > 2 {
+        names.push(format!("{}.{}{}", parts[0], parts[1], suffix));
+    }
+    names
+}
+
+#[cfg(windows)]
+fn registered_install_dir(name: &str) -> Option<PathBuf> {
+    use std::ffi::OsStr;
+    use std::os::windows::ffi::{OsStrExt, OsStringExt};
+    use std::ptr;
+
+    use self::winapi::shared::minwindef::{DWORD, HKEY};
+    use self::winapi::shared::winerror::ERROR_SUCCESS;
+    use self::winapi::um::winreg::{
+        HKEY_CURRENT_USER, HKEY_LOCAL_MACHINE, RRF_RT_REG_SZ, RegGetValueW};
+
+    // Same as PYTHON_KEY_PATHS in pythonup.metadata.
+    let keys: [(HKEY, &str); 3] = [
+        (HKEY_CURRENT_USER, "Software\\Python\\PythonCore"),
+        (HKEY_LOCAL_MACHINE, "Software\\Python\\PythonCore"),
+        (HKEY_LOCAL_MACHINE, "Software\\Wow6432Node\\Python\\PythonCore"),
+    ];
+    for &(root, prefix) in &keys {
+        let subkey = format!("{}\\{}\\InstallPath", prefix, name);
+        let subkey: Vec<u16> = OsStr::new(&subkey).encode_wide()
+            .chain(Some(0))
+            .collect();
+
+        // Ask for the size first, then read the (default) value.
+        let mut size: DWORD = 0;
+        let ok = unsafe { RegGetValueW(
+            root, subkey.as_ptr(), ptr::null(), RRF_RT_REG_SZ,
+            ptr::null_mut(), ptr::null_mut(), &mut size,
+        ) };
+        if ok as DWORD != ERROR_SUCCESS {
+            continue;
+        }
+        let mut buf: Vec<u16> = vec![0; size as usize / 2];
+        let ok = unsafe { RegGetValueW(
+            root, subkey.as_ptr(), ptr::null(), RRF_RT_REG_SZ,
+            ptr::null_mut(), buf.as_mut_ptr() as *mut _, &mut size,
+        ) };
+        if ok as DWORD != ERROR_SUCCESS {
+            continue;
+        }
+        let len = buf.iter().position(|&c| c == 0).unwrap_or(buf.len());
+        return Some(PathBuf::from(OsString::from_wide(&buf[..len])));
+    }
+    None
+}
+
+#[cfg(not(windows))]
+fn registered_install_dir(_: &str) -> Option<PathBuf> {
+    None
+}
+
+/// Find the installation directory of a version.
+///
+/// Installations are looked up in the versions directory if the shim has
+/// one, or in the registry on Windows.
+pub fn install_dir(name: &str, options: &Options) -> Option<PathBuf> {
+    match options.versions_dir() {
+        Some(dir) => Some(dir.join(name)).filter(|dir| dir.is_dir()),
+        None => registered_install_dir(name),
+    }
+}
+
+// Read JSON from a file. Missing or malformed files are treated as empty,
+// like pythonup.configs.safe_load.
+fn load_json(path: &Path) -> Value {
+    fs::read(path).ok()
+        .and_then(|data| serde_json::from_slice(&data).ok())
+        .unwrap_or(Value::Null)
+}
+
+// Paths in installation.json are written for Windows.
+fn native_path(path: &str) -> PathBuf {
+    if cfg!(windows) {
+        PathBuf::from(path)
+    } else {
+        PathBuf::from(path.replace('\\', "/"))
+    }
+}
+
+/// A directory configured in `installation.json`, e.g. `scripts_dir`.
+///
+/// Values are relative to `installation.json` itself.
+pub fn configured_dir(installation: &Path, key: &str) -> Option<PathBuf> {
+    let dir = native_path(load_json(installation)[key].as_str()?);
+    let parent = installation.parent().unwrap_or_else(|| Path::new(""));
+    Some(parent.join(dir))
+}
+
+/// pythonup's base directory, from `base_dir` in `installation.json`.
+pub fn base_dir(installation: &Path) -> Option<PathBuf> {
+    configured_dir(installation, "base_dir")
+}
+
+/// Names of active versions in pythonup's configuration.
+///
+/// This is the same as `pythonup.configs.get_active_names`. The `config`
+/// file is in the base directory.
+pub fn active_names(installation: &Path) -> Vec<String> {
+    let base_dir = match base_dir(installation) {
+        Some(dir) => dir,
+        None => { return vec![]; },
+    };
+    let config = load_json(&base_dir.join("config"));
+    match config["using"].as_array() {
+        Some(names) => names.iter()
+            .filter_map(Value::as_str)
+            .map(String::from)
+            .collect(),
+        None => vec![],
+    }
+}
+
+fn in_series(name: &str, series: &str) -> bool {
+    match name.strip_prefix(series) {
+        Some(rest) => {
+            rest.is_empty() || rest.starts_with('.') || rest.starts_with('-')
+        },
+        None => false,
+    }
+}
+
+// The command's executable in the first active version providing it.
+fn active_executable(command: &Command, relative: &Path, options: &Options)
+        -> Option<PathBuf> {
+    let installation = options.installation()?;
+    active_names(installation).iter()
+        .filter(|name| command.series().is_none_or(|s| in_series(name, s)))
+        .filter_map(|name| install_dir(name, options))
+        .map(|dir| dir.join(relative))
+        .find(|exe| exe.is_file())
+}
+
+fn resolve(relative: &Path, pin: Pin, options: &Options)
+        -> Result<PathBuf, Error> {
+    for name in candidate_names(&pin.name) {
+        if let Some(dir) = install_dir(&name, options) {
+            return Ok(dir.join(relative));
+        }
+    }
+    Err(Error::NotInstalled(pin.name, pin.source.to_string()))
+}
+
+/// Find the executable to run for a command.
+///
+/// A pinned version is used if there is one, and the first active version
+/// providing the executable otherwise. Commands without a path to resolve,
+/// or with nothing to resolve against, use the executable baked into the
+/// shim.
+pub fn executable(command: &Command, options: &Options)
+        -> Result<PathBuf, Error> {
+    let exe = command.exe().ok_or(Error::Empty)?;
+    let relative = match command.resolve() {
+        Some(relative) => relative,
+        None => { return Ok(exe.clone()); },
+    };
+    let cwd = env::current_dir().unwrap_or_default();
+    if let Some(pin) = pinned_version(env::var_os(VERSION_ENV), &cwd) {
+        return resolve(relative, pin, options);
+    }
+    Ok(active_executable(command, relative, options)
+        .unwrap_or_else(|| exe.clone()))
+}
+
+
+#[cfg(test)]
+mod resolve_test {
+    use std::env;
+    use std::ffi::OsString;
+    use std::path::{Path, PathBuf};
+    use cmds::{Command, Options};
+    use super::{
+        active_executable, active_names, candidate_names, install_dir,
+        pinned_version_within, resolve, Pin, Source, VERSION_FILE};
+    use testing::TempDir;
+
+    // Pin the version in the directory, creating it.
+    fn pin(tmp: &TempDir, relative: &str, content: &str) -> PathBuf {
+        tmp.write(Path::new(relative).join(VERSION_FILE), content)
+    }
+
+    fn pinned(name: &str, source: Source) -> Option<Pin> {
+        Some(Pin { name: String::from(name), source })
+    }
+
+    // Files outside the temporary directory are not looked up.
+    fn pinned_version(env_value: Option<OsString>, cwd: &Path)
+            -> Option<Pin> {
+        let root = cwd.ancestors()
+            .find(|dir| dir.parent() == Some(&env::temp_dir()));
+        pinned_version_within(env_value, cwd, root)
+    }
+
+    #[test]
+    fn nearest_file() {
+        let tmp = TempDir::new("nearest");
+        pin(&tmp, "", "3.6\n");
+        let inner = pin(&tmp, "a", "# comment\n\n  3.7-32  \n3.5\n");
+        let cwd = tmp.mkdir("a/b/c");
+        assert_eq!(
+            pinned_version(None, &cwd),
+            pinned("3.7-32", Source::File(inner)),
+        );
+        assert_eq!(
+            pinned_version(None, tmp.path()),
+            pinned("3.6", Source::File(tmp.join(VERSION_FILE))),
+        );
+
+        // The nearest file wins even if it pins nothing.
+        let empty = pin(&tmp, "d", "# nothing here\n");
+        assert_eq!(pinned_version(None, empty.parent().unwrap()), None);
+    }
+
+    #[test]
+    fn env_override() {
+        let tmp = TempDir::new("env");
+        pin(&tmp, "", "3.6");
+        let env = Some(OsString::from("3.7"));
+        assert_eq!(
+            pinned_version(env, tmp.path()), pinned("3.7", Source::Env),
+        );
+
+        // Empty values are the same as not set.
+        let env = Some(OsString::from(""));
+        assert_eq!(
+            pinned_version(env, tmp.path()),
+            pinned("3.6", Source::File(tmp.join(VERSION_FILE))),
+        );
+    }
+
+    #[test]
+    fn unpinned() {
+        let tmp = TempDir::new("unpinned");
+        pin(&tmp, "a", "\n# nothing here\n");
+        let system = pin(&tmp, "b", "system");
+        assert_eq!(pinned_version(None, &tmp.join("a")), None);
+        assert_eq!(pinned_version(None, system.parent().unwrap()), None);
+        assert_eq!(pinned_version(None, tmp.path()), None);
+        let env = Some(OsString::from("system"));
+        assert_eq!(pinned_version(env, tmp.path()), None);
+    }
+
+    #[test]
+    fn candidates() {
+        assert_eq!(candidate_names("3.7"), vec!["3.7"]);
+        assert_eq!(candidate_names("3.7.2"), vec!["3.7.2", "3.7"]);
+        assert_eq!(candidate_names("3.7.2-32"), vec!["3.7.2-32", "3.7-32"]);
+    }
+
+    #[test]
+    fn versions_dir() {
+        let tmp = TempDir::new("versions");
+        let installed = tmp.mkdir("3.7");
+        let mut options = Options::default();
+        options.set_versions_dir(tmp.path());
+
+        assert_eq!(install_dir("3.7", &options), Some(installed.clone()));
+        assert_eq!(install_dir("3.6", &options), None);
+
+        let pin = Pin { name: String::from("3.7.2"), source: Source::Env };
+        let exe = resolve(Path::new("bin/python"), pin, &options).unwrap();
+        assert_eq!(exe, installed.join("bin/python"));
+
+        let pin = Pin { name: String::from("3.6"), source: Source::Env };
+        let e = resolve(Path::new("bin/python"), pin, &options).unwrap_err();
+        assert_eq!(e.exit_code(), 127);
+        assert_eq!(e.to_string(), "\
+            version 3.6 pinned by PYTHONUP_VERSION is not installed\n\
+            HINT: Run \"pythonup install 3.6\" to install it.");
+    }
+
+    #[test]
+    fn active() {
+        let tmp = TempDir::new("active");
+        let installation = tmp.write(
+            "pythonup/lib/installation.json",
+            r#"{"base_dir": "..\\..", "cmd_dir": "..\\..\\cmd"}"#,
+        );
+        tmp.write("config", r#"{"using": ["2.7", "3.6", "3.7-32", "3.8"]}"#);
+        tmp.write("versions/2.7/bin/python", "");
+        tmp.write("versions/3.7-32/bin/python", "");
+        tmp.write("versions/3.8/bin/python", "");
+        tmp.mkdir("versions/3.6");
+
+        assert_eq!(
+            active_names(&installation),
+            vec!["2.7", "3.6", "3.7-32", "3.8"],
+        );
+
+        let mut options = Options::default();
+        options.set_versions_dir(tmp.join("versions"));
+        options.set_installation(&installation);
+        let relative = Path::new("bin/python");
+
+        // 3.6 is skipped since it does not provide the executable.
+        let mut command = Command::new();
+        command.set_series("3");
+        assert_eq!(
+            active_executable(&command, relative, &options),
+            Some(tmp.join("versions/3.7-32/bin/python")),
+        );
+        command.set_series("3.8");
+        assert_eq!(
+            active_executable(&command, relative, &options),
+            Some(tmp.join("versions/3.8/bin/python")),
+        );
+        command.set_series("2");
+        assert_eq!(
+            active_executable(&command, relative, &options),
+            Some(tmp.join("versions/2.7/bin/python")),
+        );
+        command.set_series("3.9");
+        assert_eq!(active_executable(&command, relative, &options), None);
+    }
+
+    #[test]
+    fn no_active() {
+        let tmp = TempDir::new("no-active");
+        let installation = tmp.write("installation.json", "{}");
+        assert!(active_names(&installation).is_empty());
+
+        tmp.write("installation.json", r#"{"base_dir": "."}"#);
+        assert!(active_names(&installation).is_empty());
+        tmp.write("config", "not json");
+        assert!(active_names(&installation).is_empty());
+        tmp.write("config", r#"{"using": ["3.7"]}"#);
+        assert_eq!(active_names(&installation), vec!["3.7"]);
+    }
+}
@@ -0,0 +1,326 @@
+//! Run scripts by their shebang lines, like distlib's launcher.
+//!
+//! A shim in launcher mode runs a script, by default the `-script.py` file
+//! next to it (e.g. `black-script.py` for `black.exe`), with the interpreter
+//! named by the script's `#!` line, or one embedded in the shim. Python
+//! interpreters are looked up like shim commands resolved against
+//! installations:
+//!
+//! | Shebang                     | Runs                                   |
+//! |-----------------------------|----------------------------------------|
+//! | `#!python3.7`               | Python 3.7, if installed.              |
+//! | `#!/usr/bin/env python3`    | The pinned or first active Python 3.   |
+//! | `#!/usr/bin/python`         | The pinned or first active Python.     |
+//! | `#!"C:\py\python.exe" -E`   | The given executable.                  |
+//!
+//! Unix-style paths to interpreters are only taken literally if they exist.
+
+use std::ffi::{OsStr, OsString};
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read};
+use std::path::Path;
+
+use cmds::{Command, Options};
+use errors::Error;
+use resolve;
+
+#[cfg(unix)] use std::os::unix::ffi::{OsStrExt, OsStringExt};
+
+#[cfg(windows)] const PYTHON: &str = "python.exe";
+#[cfg(windows)] const PYTHONW: &str = "pythonw.exe";
+#[cfg(not(windows))] const PYTHON: &str = "bin/python";
+#[cfg(not(windows))] const PYTHONW: &str = "bin/python";
+
+// Longest shebang line read from a script.
+const MAX_LINE: u64 = 4096;
+
+/// An interpreter and its arguments, from a shebang line.
+#[derive(Debug, PartialEq)]
+pub struct Shebang {
+    pub program: OsString,
+    pub args: Vec<OsString>,
+}
+
+/// Convert bytes of a shebang line to an OS string.
+#[cfg(unix)]
+pub fn os_string(bytes: &[u8]) -> OsString {
+    OsString::from_vec(bytes.to_vec())
+}
+
+/// Convert bytes of a shebang line to an OS string.
+#[cfg(windows)]
+pub fn os_string(bytes: &[u8]) -> OsString {
+    OsString::from(String::from_utf8_lossy(bytes).into_owned())
+}
+
+#[cfg(unix)]
+fn os_bytes(s: &OsStr) -> Vec<u8> {
+    s.as_bytes().to_vec()
+}
+
+#[cfg(windows)]
+fn os_bytes(s: &OsStr) -> Vec<u8> {
+    s.to_string_lossy().into_owned().into_bytes()
+}
+
+/// Parse a shebang line, with or without its leading `#!`.
+///
+/// The program may be quoted if it contains spaces. Arguments are split on
+/// whitespace. `/usr/bin/env` is skipped, so its argument is the program.
+pub fn parse(line: &[u8]) -> Option<Shebang> {
+    let line = line.strip_prefix(b"#!").unwrap_or(line);
+    let line = line.trim_ascii();
+    let (program, rest) = match line.strip_prefix(b"\"") {
+        Some(quoted) => {
+            let end = quoted.iter().position(|&b| b == b'"')?;
+            (&quoted[..end], &quoted[end + 1..])
+        },
+        None => {
+            let end = line.iter().position(u8::is_ascii_whitespace)
+                .unwrap_or(line.len());
+            (&line[..end], &line[end..])
+        },
+    };
+    let mut args: Vec<_> = rest.split(u8::is_ascii_whitespace)
+        .filter(|arg| !arg.is_empty())
+        .map(os_string)
+        .collect();
+    if program == b"/usr/bin/env" {
+        if args.is_empty() {
+            return None;
+        }
+        let program = args.remove(0);
+        return Some(Shebang { program, args });
+    }
+    if program.is_empty() {
+        return None;
+    }
+    Some(Shebang { program: os_string(program), args })
+}
+
+/// Read the shebang line of a script. `None` is returned if it has none.
+pub fn read(script: &Path) -> io::Result<Option<Shebang>> {
+    let mut line = vec![];
+    let file = File::open(script)?;
+    BufReader::new(file.take(MAX_LINE)).read_until(b'\n', &mut line)?;
+    if !line.starts_with(b"#!") {
+        return Ok(None);
+    }
+    Ok(parse(&line))
+}
+
+// Version in a Python interpreter's name, e.g. `Some("3.7")` for
+// `python3.7.exe`, or `Some("")` for `pythonw`. `None` if not Python.
+fn python_version(name: &str) -> Option<(&str, bool)> {
+    let name = name.strip_suffix(".exe").unwrap_or(name);
+    let rest = name.strip_prefix("python")?;
+    let (version, windowed) = match rest.strip_prefix('w') {
+        Some(version) => (version, true),
+        None => (rest, false),
+    };
+    let numeric = version.split(['.', '-']).all(|part| {
+        part.chars().all(|c| c.is_ascii_digit()) || part == "32"
+    });
+    if !numeric || version.starts_with(['.', '-']) {
+        return None;
+    }
+    Some((version, windowed))
+}
+
+// Whether the program is to be looked up by name: a bare name, or a
+// Unix-style path that does not exist here, e.g. `/usr/bin/python3` on
+// Windows.
+fn by_name(program: &Path) -> bool {
+    let bare = program.parent().is_none_or(|p| p.as_os_str().is_empty());
+    let unix_style = os_bytes(program.as_os_str()).starts_with(b"/");
+    bare || (unix_style && !program.exists())
+}
+
+/// Build the command to run a script with its shebang.
+///
+/// Python interpreters named with a full version, e.g. `python3.7`, run
+/// from the version's installation. Others resolve to the pinned version,
+/// or the first active one in their series, like shim commands with
+/// `resolve`, falling back to the name. Other programs run as given.
+pub fn command(shebang: &Shebang, script: &Path, options: &Options)
+        -> Result<Command, Error> {
+    let program = Path::new(&shebang.program);
+    let python = program.file_name().and_then(OsStr::to_str)
+        .filter(|_| by_name(program))
+        .and_then(|name| Some((name, python_version(name)?)));
+    let mut command = Command::new();
+    match python {
+        Some((name, (version, windowed))) => {
+            let relative = if windowed { PYTHONW } else { PYTHON };
+            if version.contains('.') {
+                let dir = resolve::candidate_names(version).iter()
+                    .find_map(|name| resolve::install_dir(name, options))
+                    .ok_or_else(|| Error::NotInstalled(
+                        String::from(version), script.display().to_string(),
+                    ))?;
+                command.arg(dir.join(relative));
+            } else {
+                command.arg(name);
+                command.set_resolve(relative);
+                if !version.is_empty() {
+                    command.set_series(version);
+                }
+            }
+            command.set_prepends_installation(true);
+        },
+        None => { command.arg(program); },
+    }
+    for arg in &shebang.args {
+        command.arg(arg);
+    }
+    command.arg(script);
+    Ok(command)
+}
+
+/// Build the command to run a shim's script in launcher mode.
+///
+/// The shebang embedded in the shim is used if there is one, and the
+/// script's own otherwise.
+pub fn script_command(script: &Path, options: &Options)
+        -> Result<Command, Error> {
+    let spawn_error = |e| Error::Spawn(script.to_path_buf(), e);
+    let shebang = match options.shebang() {
+        Some(line) => parse(&os_bytes(line)),
+        None => read(script).map_err(spawn_error)?,
+    };
+    let shebang = shebang.ok_or_else(|| spawn_error(io::Error::new(
+        io::ErrorKind::InvalidData, "no shebang line",
+    )))?;
+    command(&shebang, script, options)
+}
+
+
+#[cfg(test)]
+mod shebang_test {
+    use std::env;
+    use std::ffi::OsString;
+    use std::fs;
+    use std::path::{Path, PathBuf};
+    use cmds::Options;
+    use super::{command, parse, script_command, Shebang, PYTHON, PYTHONW};
+    use testing::TempDir;
+
+    fn shebang(program: &str, args: &[&str]) -> Option<Shebang> {
+        Some(Shebang {
+            program: OsString::from(program),
+            args: args.iter().map(OsString::from).collect(),
+        })
+    }
+
+    // A versions directory with 3.7 installed.
+    fn versions(name: &str) -> TempDir {
+        let versions = TempDir::new(name);
+        versions.mkdir("3.7");
+        versions
+    }
+
+    fn with_versions(versions: &TempDir) -> Options {
+        let mut options = Options::default();
+        options.set_versions_dir(versions.path());
+        options
+    }
+
+    #[test]
+    fn parse_lines() {
+        assert_eq!(parse(b"#!python3.7\r\n"), shebang("python3.7", &[]));
+        assert_eq!(
+            parse(b"#!/usr/bin/env python3 -u -E\n"),
+            shebang("python3", &["-u", "-E"]),
+        );
+        assert_eq!(
+            parse(b"#!\"C:\\Program Files\\Python\\python.exe\" -E"),
+            shebang("C:\\Program Files\\Python\\python.exe", &["-E"]),
+        );
+        assert_eq!(
+            parse(b"  /usr/bin/python  "), shebang("/usr/bin/python", &[]),
+        );
+        assert_eq!(parse(b"#!"), None);
+        assert_eq!(parse(b"#!/usr/bin/env"), None);
+        assert_eq!(parse(b"#!\"unterminated"), None);
+    }
+
+    #[test]
+    fn versioned_python() {
+        let versions = versions("shebang-versioned");
+        let options = with_versions(&versions);
+        let script = Path::new("black-script.py");
+
+        let line = shebang("python3.7", &["-E"]).unwrap();
+        let command = command(&line, script, &options).unwrap();
+        assert_eq!(command.exe(), Some(&versions.join("3.7").join(PYTHON)));
+        assert_eq!(command.args(), &vec![
+            OsString::from("-E"), OsString::from("black-script.py"),
+        ]);
+        assert!(command.prepends_installation());
+
+        let line = shebang("/usr/bin/pythonw3.7.exe", &[]).unwrap();
+        let command = super::command(&line, script, &options).unwrap();
+        assert_eq!(command.exe(), Some(&versions.join("3.7").join(PYTHONW)));
+
+        let line = shebang("python3.6", &[]).unwrap();
+        let e = super::command(&line, script, &options).unwrap_err();
+        assert_eq!(e.exit_code(), 127);
+    }
+
+    #[test]
+    fn unversioned_python() {
+        let options = Options::default();
+        let script = Path::new("black-script.py");
+        for &(program, series) in &[
+                ("python3", Some("3")), ("/nonexistent/bin/python", None)] {
+            let line = shebang(program, &[]).unwrap();
+            let command = command(&line, script, &options).unwrap();
+            let name = Path::new(program).file_name().unwrap();
+            assert_eq!(command.exe(), Some(&PathBuf::from(name)));
+            assert_eq!(command.resolve(), Some(&PathBuf::from(PYTHON)));
+            assert_eq!(command.series(), series);
+        }
+    }
+
+    #[test]
+    fn other_programs() {
+        let options = Options::default();
+        let script = Path::new("tool-script.py");
+        for program in &["perl", "/nonexistent/bin/ruby", "pythonista"] {
+            let line = shebang(program, &[]).unwrap();
+            let command = command(&line, script, &options).unwrap();
+            assert_eq!(command.exe(), Some(&PathBuf::from(program)));
+            assert_eq!(command.resolve(), None);
+            assert!(!command.prepends_installation());
+        }
+
+        // Existing paths are used literally, even for Python.
+        let exe = env::current_exe().unwrap();
+        let line = Shebang { program: exe.clone().into(), args: vec![] };
+        let command = command(&line, script, &options).unwrap();
+        assert_eq!(command.exe(), Some(&exe));
+    }
+
+    #[test]
+    fn scripts() {
+        let versions = versions("shebang-scripts");
+        let script = versions.join("black-script.py");
+        let mut options = with_versions(&versions);
+
+        fs::write(&script, "#!python3.7 -E\r\nimport black\n").unwrap();
+        let command = script_command(&script, &options).unwrap();
+        assert_eq!(command.exe(), Some(&versions.join("3.7").join(PYTHON)));
+
+        options.set_shebang("/usr/bin/env perl");
+        let command = script_command(&script, &options).unwrap();
+        assert_eq!(command.exe(), Some(&PathBuf::from("perl")));
+
+        let options = with_versions(&versions);
+        fs::write(&script, "import black\n").unwrap();
+        let e = script_command(&script, &options).unwrap_err();
+        assert_eq!(e.exit_code(), 126);
+        fs::remove_file(&script).unwrap();
+        let e = script_command(&script, &options).unwrap_err();
+        assert_eq!(e.exit_code(), 127);
+    }
+}
@@ -0,0 +1,50 @@
+//! Fixtures shared by unit tests.
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// A directory tree unique to each test, removed afterwards.
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    /// Create an empty directory. `name` must be unique among tests.
+    pub fn new(name: &str) -> Self {
+        let path = env::temp_dir().join(format!(
+            "pythonup-shim-{}-{}", name, ::std::process::id(),
+        ));
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).unwrap();
+        TempDir(path)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+
+    pub fn join<P: AsRef<Path>>(&self, relative: P) -> PathBuf {
+        self.0.join(relative)
+    }
+
+    /// Create a directory in the tree, with its parents.
+    pub fn mkdir<P: AsRef<Path>>(&self, relative: P) -> PathBuf {
+        let path = self.0.join(relative);
+        fs::create_dir_all(&path).unwrap();
+        path
+    }
+
+    /// Write a file in the tree, creating its parents.
+    pub fn write<P, C>(&self, relative: P, content: C) -> PathBuf
+            where P: AsRef<Path>, C: AsRef<[u8]> {
+        let path = self.0.join(relative);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, content).unwrap();
+        path
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
@@ -0,0 +1,1087 @@
+//! Command line tool to manage shims.
+//!
+//! Commands are given after `--`, and are separated by `--then` if the shim
+//! should run more than one of them:
+//!
+//! ```text
+//! shim-tool create --out pip3.exe -- C:\py\Scripts\pip.exe \
+//!     --then -- C:\py\python.exe -m pythonup link --all
+//! ```
+//!
+//! Options given before a command's `--` apply to that command. With
+//! `--resolve`, the executable is looked up relative to the installation of
+//! the version pinned for the working directory (if any) when the shim runs:
+//!
+//! ```text
+//! shim-tool create --out python3.exe --resolve python.exe \
+//!     -- C:\py\python.exe
+//! ```
+//!
+//! With `--installation`, such commands use the first active version in
+//! pythonup's configuration if nothing is pinned. `--series` limits this to
+//! versions in a series, e.g. `3` for `python3.exe`.
+//!
+//! `--env`, `--unset`, and `--prepend-path` change the command's environment,
+//! in the order given:
+//!
+//! ```text
+//! shim-tool create --out python3.exe --env PYTHONUTF8 1 --unset PYTHONPATH \
+//!     --prepend-path PATH C:\py\Scripts -- C:\py\python.exe
+//! ```
+//!
+//! `--cwd` runs the command in another directory.
+//!
+//! Commands may contain placeholders, expanded when the shim runs:
+//! `{shim_dir}`, `{shim_name}`, `{base_dir}` (with `--installation`), and
+//! environment variables as `%VAR%` or `${VAR}`. Double `{`, `}`, `%`, or `$`
+//! to keep it literally:
+//!
+//! ```text
+//! shim-tool create --out pip3.exe --cwd {shim_dir} \
+//!     -- {shim_dir}\..\py\Scripts\pip.exe --cache-dir %TEMP%\pip
+//! ```
+//!
+//! Arguments passed to the shim are appended to its first command, unless
+//! placed explicitly with `{args}` or `{argN}`, or dropped with `--no-args`:
+//!
+//! ```text
+//! shim-tool create --out tool.exe \
+//!     -- C:\py\python.exe -m tool {args} --config C:\tool.ini
+//! ```
+//!
+//! A command runs only if the one before it succeeded, unless changed with
+//! `--when always` or `--when failure`. The shim exits with the last run
+//! command's code, or the first's or worst's with `--report`:
+//!
+//! ```text
+//! shim-tool create --out pip3.exe --report first -- C:\py\Scripts\pip.exe \
+//!     --then --when always -- C:\py\python.exe -m pythonup link --all
+//! ```
+//!
+//! With `--detach`, a command is started in the background, and the shim
+//! does not wait for it. If the same command is still running, it is queued
+//! to run once more after it, however many times the shim is launched.
+//!
+//! With `--watch`, commands after the first only run if the first changes
+//! the listing, sizes, or modification times of files in a directory:
+//!
+//! ```text
+//! shim-tool create --out pip3.exe --watch C:\py\Scripts \
+//!     -- C:\py\Scripts\pip.exe \
+//!     --then -- C:\py\python.exe -m pythonup link --all
+//! ```
+//!
+//! `inspect` prints what an existing shim runs:
+//!
+//! ```text
+//! shim-tool inspect [--json] python3.exe
+//! ```
+//!
+//! With `--digest`, the shim records the SHA-256 of a command's executable,
+//! and warns if it changed when run, or refuses to run it with
+//! `--on-change refuse`. `verify` checks shims in pythonup's `cmd` and
+//! `Scripts` directories, or those given. Legacy shims, and files it cannot
+//! read as shims, are reported as unchecked:
+//!
+//! ```text
+//! shim-tool create --out python3.7.exe --on-change refuse \
+//!     --digest -- C:\py\python.exe
+//! shim-tool verify --installation C:\pythonup\installation.json
+//! ```
+//!
+//! `keygen` creates a key for the user. Shims then only run if signed with
+//! it, with `--sign`:
+//!
+//! ```text
+//! shim-tool keygen
+//! shim-tool create --out python3.7.exe --sign -- C:\py\python.exe
+//! ```
+//!
+//! With `--launcher`, the shim runs the `-script.py` file next to it, with
+//! the interpreter in the script's shebang line, like pip's own launchers.
+//! `--script` runs another script, and `--shebang` overrides its shebang.
+//! Commands given are run after it:
+//!
+//! ```text
+//! shim-tool create --out black.exe --launcher
+//! ```
+//!
+//! With `--zipapp`, a zip archive, e.g. a `.pyz` file, is appended to the
+//! shim, which runs it with the interpreter in its shebang line, or
+//! `--shebang`. This makes single-file tools:
+//!
+//! ```text
+//! shim-tool create --out black.exe --zipapp black.pyz
+//! ```
+//!
+//! `relink` links scripts of active versions into pythonup's scripts
+//! directory, like `pythonup link --all`, without starting an interpreter.
+//! Shims it writes for pip run it again if pip changes scripts:
+//!
+//! ```text
+//! shim-tool relink --installation C:\pythonup\installation.json \
+//!     --overwrite smart
+//! ```
+
+extern crate shim;
+#[macro_use] extern crate serde_json;
+
+use std::env::{self, consts};
+use std::ffi::{OsStr, OsString};
+use std::fs;
+use std::io::{self, Write};
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::process::exit;
+
+use shim::auth;
+use shim::cmds::{
+    Command, Commands, Condition, EnvOp, OnChange, Options, Placeholders,
+    Report};
+use shim::digest::{hex, sha256_file};
+use shim::relink::{exe_command, Linker, Overwrite, Version};
+use shim::resolve;
+use shim::verify::{self, Status};
+use shim::writer::{escape, write_shim, write_zipapp_shim};
+use shim::zipapp::Zipapp;
+
+const USAGE: &str = "\
+usage: shim-tool create [--base <file>] --out <file> [--versions-dir <dir>]
+                        [--installation <file>] [--report <code>]
+                        [--watch <dir>] [--on-change <action>] [--sign]
+                        [--max-depth <n>] [--launcher | --script <file>
+                        | --zipapp <file>] [--shebang <line>]
+                        [--resolve <path>] [--series <version>]
+                        [--env <name> <value>] [--unset <name>]
+                        [--prepend-path <name> <entry>] [--cwd <dir>]
+                        [--no-args] [--when <result>] [--detach]
+                        [--digest] [--install-path | --keep-path]
+                        -- <exe> [<arg>...] [--then ... -- <exe> [<arg>...]]...
+       shim-tool inspect [--json] <file>
+       shim-tool relink --installation <file> [--versions-dir <dir>]
+                        [--overwrite <when>] [--quiet]
+       shim-tool verify [--installation <file>] [<file>...]
+       shim-tool keygen [--force]
+
+commands:
+    create      Write a shim running the given commands.
+    inspect     Show commands embedded in a shim.
+    relink      Link scripts of active versions, like `pythonup link --all`.
+    verify      Check executables of shims against their recorded digests.
+    keygen      Create a key for the user. Shims must then be signed with it
+                to run.
+
+options:
+    --base      Base executable to write the shim with. Defaults to the shim
+                executable next to this tool.
+    --out       Path to write the shim to.
+    --versions-dir
+                Directory containing installations named by version, to look
+                up pinned versions in. Defaults to the registry on Windows.
+    --installation
+                Path to pythonup's installation.json, to look up active
+                versions with if no versions are pinned.
+    --resolve   Path of the command's executable relative to an installation,
+                used instead of <exe> if a version is pinned or active.
+    --series    Only use active versions in this series, e.g. 3.
+    --env       Set an environment variable for the command.
+    --unset     Remove an environment variable for the command.
+    --prepend-path
+                Prepend an entry to a path-like environment variable, e.g.
+                PATH, for the command.
+    --cwd       Working directory of the command.
+    --no-args   Do not append arguments passed to the shim to the command.
+    --when      When to run the command, depending on the previous one's
+                result: success (default), failure, or always.
+    --detach    Start the command in the background without waiting for
+                it. If it is still running from a previous launch, it runs
+                once more afterwards.
+    --report    Exit code of the shim if it runs several commands: that of
+                the first, last (default), or worst command run.
+    --watch     Only run commands after the first if it changes files in
+                this directory.
+    --digest    Record the SHA-256 of the command's executable, to check
+                it against when the shim runs.
+    --install-path
+                Put the installation of the command's executable first in
+                its PATH, instead of the shim's directory. This is the
+                default for Python interpreters run without arguments,
+                e.g. python3.exe.
+    --keep-path Pass PATH on to the command unchanged.
+    --on-change What to do if an executable does not match its digest:
+                warn (default), or refuse to run it.
+    --sign      Sign the shim with the user's key.
+    --launcher  Run the script next to the shim, named after it like
+                black-script.py for black.exe, with its shebang, before
+                any commands.
+    --script    Run this script with its shebang, like --launcher.
+    --zipapp    Append this zip archive, e.g. a .pyz file, to the shim, and
+                run it with its shebang, like --launcher.
+    --shebang   Shebang line to run the script with instead of its own,
+                e.g. `python3.7` or `/usr/bin/env python3`.
+    --max-depth How many shims may run each other before this one refuses
+                to run, in case one runs itself. Defaults to 16.
+    --json      Output in JSON instead of text.
+    --overwrite What to do if a script exists: yes (default), no, or smart
+                (only if different).
+    --quiet     Do not list scripts written or removed.
+    --force     Replace an existing key. Shims signed with it stop working.
+
+Commands and --watch may contain {shim_dir}, {shim_name}, {base_dir}, %VAR%,
+and ${VAR}, expanded when the shim runs. Double {, }, %, or $ to escape them.
+Arguments passed to the shim are placed with {args}, or {arg1}, {arg2}, etc.
+
+The key is read from the file named by PYTHONUP_SHIM_KEY, which must exist if
+set, or pythonup\\shim.key in %LOCALAPPDATA% ($XDG_CONFIG_HOME or ~/.config on
+Unix). Once a key exists, unsigned and legacy shims refuse to run.";
+
+#[derive(Debug)]
+enum Error {
+    Usage(String),
+    Failure(String),
+}
+
+fn default_base() -> Result<PathBuf, Error> {
+    let exe = env::current_exe().map_err(|e| Error::Failure(e.to_string()))?;
+    Ok(exe.with_file_name(format!("shim{}", consts::EXE_SUFFIX)))
+}
+
+fn value<I>(args: &mut I, name: &str) -> Result<OsString, Error>
+        where I: Iterator<Item=OsString> {
+    args.next().ok_or_else(|| {
+        Error::Usage(format!("{} requires a value", name))
+    })
+}
+
+fn condition(value: OsString) -> Result<Condition, Error> {
+    match value.to_str() {
+        Some("always") => Ok(Condition::Always),
+        Some("success") => Ok(Condition::OnSuccess),
+        Some("failure") => Ok(Condition::OnFailure),
+        _ => Err(Error::Usage(format!("bad --when {:?}", value))),
+    }
+}
+
+fn condition_name(condition: Condition) -> &'static str {
+    match condition {
+        Condition::Always => "always",
+        Condition::OnSuccess => "success",
+        Condition::OnFailure => "failure",
+    }
+}
+
+fn report(value: OsString) -> Result<Report, Error> {
+    match value.to_str() {
+        Some("first") => Ok(Report::First),
+        Some("last") => Ok(Report::Last),
+        Some("worst") => Ok(Report::Worst),
+        _ => Err(Error::Usage(format!("bad --report {:?}", value))),
+    }
+}
+
+fn report_name(report: Report) -> &'static str {
+    match report {
+        Report::First => "first",
+        Report::Last => "last",
+        Report::Worst => "worst",
+    }
+}
+
+fn on_change(value: OsString) -> Result<OnChange, Error> {
+    match value.to_str() {
+        Some("warn") => Ok(OnChange::Warn),
+        Some("refuse") => Ok(OnChange::Refuse),
+        _ => Err(Error::Usage(format!("bad --on-change {:?}", value))),
+    }
+}
+
+fn on_change_name(on_change: OnChange) -> &'static str {
+    match on_change {
+        OnChange::Warn => "warn",
+        OnChange::Refuse => "refuse",
+    }
+}
+
+// Interpreters run by shims find their own installation in PATH first.
+// Only bare interpreters, e.g. python3.7.exe, count, not ones running a
+// module or script, or other programs named python-something.
+fn is_interpreter(command: &Command) -> bool {
+    let name = match command.exe().and_then(|exe| exe.file_name()) {
+        Some(name) => name.to_string_lossy().to_lowercase(),
+        None => { return false; },
+    };
+    let name = name.strip_suffix(".exe").unwrap_or(&name);
+    let version = match name.strip_prefix("python") {
+        Some(version) => version,
+        None => { return false; },
+    };
+    let mut parts = version.split('.');
+    let is_version = match (parts.next(), parts.next(), parts.next()) {
+        (Some(""), None, None) => true,
+        (Some(major), minor, None) => {
+            let number = |s: &str| {
+                !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit())
+            };
+            number(major) && minor.is_none_or(number)
+        },
+        _ => false,
+    };
+    is_version && command.args().is_empty()
+}
+
+fn key() -> Result<Option<Vec<u8>>, Error> {
+    auth::user_key().map_err(|e| Error::Failure(e.to_string()))
+}
+
+// A shim to write, as given to `create`.
+#[derive(Debug)]
+struct Spec {
+    base: Option<PathBuf>,
+    out: PathBuf,
+    commands: Vec<Command>,
+    options: Options,
+    sign: bool,
+    app: Option<Vec<u8>>,
+}
+
+fn parse_create<I>(mut args: I) -> Result<Spec, Error>
+        where I: Iterator<Item=OsString> {
+    let mut base = None;
+    let mut out = None;
+    let mut options = Options::default();
+    let mut commands = vec![];
+
+    // Options are collected into the command until its "--" is seen.
+    let mut command = Command::new();
+    let mut digest = false;
+    let mut install_path = None;
+    let mut sign = false;
+    let mut app = None;
+
+    while let Some(arg) = args.next() { match arg.to_str() {
+        Some("--base") => {
+            base = Some(PathBuf::from(value(&mut args, "--base")?));
+        },
+        Some("--out") => {
+            out = Some(PathBuf::from(value(&mut args, "--out")?));
+        },
+        Some("--versions-dir") => {
+            options.set_versions_dir(value(&mut args, "--versions-dir")?);
+        },
+        Some("--installation") => {
+            options.set_installation(value(&mut args, "--installation")?);
+        },
+        Some("--watch") => {
+            options.set_watch_dir(value(&mut args, "--watch")?);
+        },
+        Some("--report") => {
+            options.set_report(report(value(&mut args, "--report")?)?);
+        },
+        Some("--on-change") => {
+            let value = value(&mut args, "--on-change")?;
+            options.set_on_change(on_change(value)?);
+        },
+        Some("--sign") => { sign = true; },
+        Some("--launcher") => {
+            let path = Path::new("{shim_dir}").join("{shim_name}-script.py");
+            options.set_script(path);
+        },
+        Some("--script") => {
+            options.set_script(value(&mut args, "--script")?);
+        },
+        Some("--shebang") => {
+            options.set_shebang(value(&mut args, "--shebang")?);
+        },
+        Some("--zipapp") => {
+            let path = PathBuf::from(value(&mut args, "--zipapp")?);
+            let data = fs::read(&path).map_err(|e| Error::Failure(format!(
+                "failed to read {}: {}", path.display(), e,
+            )))?;
+            options.set_zipapp(Zipapp::of(&data));
+            app = Some(data);
+        },
+        Some("--max-depth") => {
+            let value = value(&mut args, "--max-depth")?;
+            let depth = value.to_str().and_then(|v| v.parse().ok())
+                .ok_or_else(|| {
+                    Error::Usage(format!("bad --max-depth {:?}", value))
+                })?;
+            options.set_max_depth(depth);
+        },
+        Some("--series") => {
+            let value = value(&mut args, "--series")?;
+            command.set_series(value.into_string().map_err(|v| {
+                Error::Usage(format!("bad --series {:?}", v))
+            })?);
+        },
+        Some("--resolve") => {
+            command.set_resolve(value(&mut args, "--resolve")?);
+        },
+        Some("--env") => {
+            let name = value(&mut args, "--env")?;
+            let value = value(&mut args, "--env")?;
+            command.push_env(EnvOp::Set(name, value));
+        },
+        Some("--unset") => {
+            command.push_env(EnvOp::Unset(value(&mut args, "--unset")?));
+        },
+        Some("--prepend-path") => {
+            let name = value(&mut args, "--prepend-path")?;
+            let entry = value(&mut args, "--prepend-path")?;
+            command.push_env(EnvOp::PrependPath(name, entry));
+        },
+        Some("--cwd") => {
+            command.set_working_dir(value(&mut args, "--cwd")?);
+        },
+        Some("--no-args") => { command.set_appends_own_args(false); },
+        Some("--detach") => { command.set_detached(true); },
+        Some("--digest") => { digest = true; },
+        Some("--install-path") => { install_path = Some(true); },
+        Some("--keep-path") => { install_path = Some(false); },
+        Some("--when") => {
+            command.set_condition(condition(value(&mut args, "--when")?)?);
+        },
+        Some("--") => {
+            for arg in args.by_ref() {
+                if arg == "--then" {
+                    break;
+                }
+                command.arg(arg);
+            }
+            let exe = match command.exe() {
+                Some(exe) => exe.clone(),
+                None => {
+                    return Err(Error::Usage(String::from("empty command")));
+                },
+            };
+            if mem::take(&mut digest) {
+                command.set_exe_digest(sha256_file(&exe).map_err(|e| {
+                    Error::Failure(format!(
+                        "failed to read {}: {}", exe.display(), e,
+                    ))
+                })?);
+            }
+            let prepends = install_path.take()
+                .unwrap_or_else(|| is_interpreter(&command));
+            command.set_prepends_installation(prepends);
+            commands.push(mem::take(&mut command));
+        },
+        _ => { return Err(Error::Usage(format!("unexpected {:?}", arg))); },
+    }}
+
+    let out = out.ok_or_else(|| Error::Usage(String::from("missing --out")))?;
+    if options.script().is_some() && app.is_some() {
+        return Err(Error::Usage(String::from(
+            "--zipapp cannot be used with --launcher or --script",
+        )));
+    }
+    if commands.is_empty() && options.script().is_none() && app.is_none() {
+        return Err(Error::Usage(String::from("no commands given")));
+    }
+    if command != Command::default() || digest || install_path.is_some() {
+        return Err(Error::Usage(String::from("option without command")));
+    }
+    Ok(Spec { base, out, commands, options, sign, app })
+}
+
+fn create<I>(args: I) -> Result<(), Error>
+        where I: Iterator<Item=OsString> {
+    let Spec { base, out, commands, options, sign, app } = parse_create(args)?;
+    let base = match base {
+        Some(base) => base,
+        None => default_base()?,
+    };
+    let key = if sign {
+        let key = key()?.ok_or_else(|| Error::Failure(String::from(
+            "no key to sign with; create one with `shim-tool keygen`",
+        )))?;
+        Some(key)
+    } else {
+        None
+    };
+    let key = key.as_ref().map(|key| &key[..]);
+    let result = match app {
+        Some(app) => {
+            write_zipapp_shim(&base, &out, &commands, &options, key, &app)
+        },
+        None => write_shim(&base, &out, &commands, &options, key),
+    };
+    result.map_err(|e| {
+        Error::Failure(format!("failed to write {}: {}", out.display(), e))
+    })
+}
+
+// Show the string as-is if possible, or escaped if it is not valid Unicode.
+fn display(s: &OsStr) -> String {
+    match s.to_str() {
+        Some(s) => String::from(s),
+        None => format!("{:?}", s),
+    }
+}
+
+fn print_text(commands: &[Command], options: &Options) {
+    if let Some(dir) = options.versions_dir() {
+        println!("versions dir  {}", display(dir.as_os_str()));
+    }
+    if let Some(path) = options.installation() {
+        println!("installation  {}", display(path.as_os_str()));
+    }
+    if let Some(dir) = options.watch_dir() {
+        println!("watch  {}", display(dir.as_os_str()));
+    }
+    if options.report() != Report::default() {
+        println!("report  {}", report_name(options.report()));
+    }
+    if options.on_change() != OnChange::default() {
+        println!("on change  {}", on_change_name(options.on_change()));
+    }
+    if options.has_max_depth() {
+        println!("max depth  {}", options.max_depth());
+    }
+    if let Some(path) = options.script() {
+        println!("script  {}", display(path.as_os_str()));
+    }
+    if let Some(line) = options.shebang() {
+        println!("shebang  {}", display(line));
+    }
+    if let Some(zipapp) = options.zipapp() {
+        println!("zipapp  {} bytes, {}", zipapp.size, hex(&zipapp.digest));
+    }
+    if commands.is_empty() && options.script().is_none()
+            && options.zipapp().is_none() {
+        println!("no commands");
+    }
+    for (i, command) in commands.iter().enumerate() {
+        println!("command {}", i + 1);
+        if let Some(exe) = command.exe() {
+            println!("  exe  {}", display(exe.as_os_str()));
+        }
+        for arg in command.args() {
+            println!("  arg  {}", display(arg));
+        }
+        if let Some(digest) = command.exe_digest() {
+            println!("  digest  {}", hex(digest));
+        }
+        for op in command.env() {
+            match *op {
+                EnvOp::Set(ref name, ref value) => println!(
+                    "  env  {}={}", display(name), display(value),
+                ),
+                EnvOp::Unset(ref name) => println!(
+                    "  env  unset {}", display(name),
+                ),
+                EnvOp::PrependPath(ref name, ref entry) => println!(
+                    "  env  prepend {} {}", display(name), display(entry),
+                ),
+            }
+        }
+        if let Some(dir) = command.working_dir() {
+            println!("  cwd  {}", display(dir.as_os_str()));
+        }
+        if !command.appends_own_args() {
+            println!("  no own args");
+        }
+        if command.condition() != Condition::default() {
+            println!("  when  {}", condition_name(command.condition()));
+        }
+        if command.prepends_installation() {
+            println!("  installation first in PATH");
+        }
+        if command.detached() {
+            println!("  detached");
+        }
+        if let Some(path) = command.resolve() {
+            println!("  resolve  {}", display(path.as_os_str()));
+        }
+        if let Some(series) = command.series() {
+            println!("  series  {}", series);
+        }
+    }
+}
+
+fn env_json(op: &EnvOp) -> serde_json::Value {
+    match *op {
+        EnvOp::Set(ref name, ref value) => json!({
+            "op": "set",
+            "name": name.to_string_lossy(),
+            "value": value.to_string_lossy(),
+        }),
+        EnvOp::Unset(ref name) => json!({
+            "op": "unset",
+            "name": name.to_string_lossy(),
+        }),
+        EnvOp::PrependPath(ref name, ref entry) => json!({
+            "op": "prepend-path",
+            "name": name.to_string_lossy(),
+            "value": entry.to_string_lossy(),
+        }),
+    }
+}
+
+fn print_json(path: &Path, commands: &[Command], options: &Options) {
+    let commands: Vec<_> = commands.iter().map(|command| json!({
+        "exe": command.exe().map(|exe| exe.to_string_lossy()),
+        "args": command.args().iter().map(|arg| arg.to_string_lossy())
+            .collect::<Vec<_>>(),
+        "env": command.env().iter().map(env_json).collect::<Vec<_>>(),
+        "cwd": command.working_dir().map(|dir| dir.to_string_lossy()),
+        "appends_own_args": command.appends_own_args(),
+        "when": condition_name(command.condition()),
+        "detached": command.detached(),
+        "prepends_installation": command.prepends_installation(),
+        "resolve": command.resolve().map(|path| path.to_string_lossy()),
+        "series": command.series(),
+        "digest": command.exe_digest().map(|digest| hex(digest)),
+    })).collect();
+    let value = json!({
+        "path": path.to_string_lossy(),
+        "commands": commands,
+        "versions_dir": options.versions_dir()
+            .map(|dir| dir.to_string_lossy()),
+        "installation": options.installation()
+            .map(|path| path.to_string_lossy()),
+        "report": report_name(options.report()),
+        "watch_dir": options.watch_dir().map(|dir| dir.to_string_lossy()),
+        "on_change": on_change_name(options.on_change()),
+        "max_depth": options.max_depth(),
+        "script": options.script().map(|path| path.to_string_lossy()),
+        "shebang": options.shebang().map(|line| line.to_string_lossy()),
+        "zipapp": options.zipapp().map(|zipapp| json!({
+            "size": zipapp.size,
+            "digest": hex(&zipapp.digest),
+        })),
+    });
+    println!("{}", serde_json::to_string_pretty(&value).unwrap());
+}
+
+fn inspect<I>(args: I) -> Result<(), Error>
+        where I: Iterator<Item=OsString> {
+    let mut as_json = false;
+    let mut path = None;
+    for arg in args { match arg.to_str() {
+        Some("--json") => { as_json = true; },
+        _ if path.is_none() => { path = Some(PathBuf::from(arg)); },
+        _ => { return Err(Error::Usage(format!("unexpected {:?}", arg))); },
+    }}
+
+    let path = path.ok_or_else(|| Error::Usage(String::from("missing file")))?;
+    let mut commands = Commands::from_path(&path).map_err(|e| {
+        Error::Failure(format!("failed to read {}: {}", path.display(), e))
+    })?;
+    let list: Vec<_> = commands.by_ref().collect();
+
+    if as_json {
+        print_json(&path, &list, commands.options());
+    } else {
+        print_text(&list, commands.options());
+    }
+    Ok(())
+}
+
+fn overwrite(value: OsString) -> Result<Overwrite, Error> {
+    match value.to_str() {
+        Some("yes") => Ok(Overwrite::Yes),
+        Some("no") => Ok(Overwrite::No),
+        Some("smart") => Ok(Overwrite::Smart),
+        _ => Err(Error::Usage(format!("bad --overwrite {:?}", value))),
+    }
+}
+
+fn configured_dir(installation: &Path, key: &str) -> Result<PathBuf, Error> {
+    resolve::configured_dir(installation, key).ok_or_else(|| {
+        Error::Failure(format!(
+            "{} not found in {}", key, installation.display(),
+        ))
+    })
+}
+
+fn relink<I>(mut args: I) -> Result<(), Error>
+        where I: Iterator<Item=OsString> {
+    let mut installation = None;
+    let mut options = Options::default();
+    let mut overwrite_policy = Overwrite::default();
+    let mut quiet = false;
+    while let Some(arg) = args.next() { match arg.to_str() {
+        Some("--installation") => {
+            let path = PathBuf::from(value(&mut args, "--installation")?);
+            installation = Some(path);
+        },
+        Some("--versions-dir") => {
+            options.set_versions_dir(value(&mut args, "--versions-dir")?);
+        },
+        Some("--overwrite") => {
+            overwrite_policy = overwrite(value(&mut args, "--overwrite")?)?;
+        },
+        Some("--quiet") => { quiet = true; },
+        _ => { return Err(Error::Usage(format!("unexpected {:?}", arg))); },
+    }}
+
+    let installation = installation.ok_or_else(|| {
+        Error::Usage(String::from("missing --installation"))
+    })?;
+    let scripts_dir = configured_dir(&installation, "scripts_dir")?;
+    let shims_dir = configured_dir(&installation, "shims_dir")?;
+    let base = shims_dir.join(format!("shim{}", consts::EXE_SUFFIX));
+
+    let names = resolve::active_names(&installation);
+    if names.is_empty() {
+        return Err(Error::Failure(String::from("not using any versions")));
+    }
+    let mut versions = vec![];
+    for name in names {
+        match resolve::install_dir(&name, &options) {
+            Some(dir) => versions.push(Version::new(name, dir)),
+            None => eprintln!("shim-tool: version {} not installed", name),
+        }
+    }
+
+    // Shims for pip run this again, from anywhere.
+    let cwd = env::current_dir().unwrap_or_default();
+    let exe = env::current_exe().map_err(|e| Error::Failure(e.to_string()))?;
+    let mut command = exe_command(&exe);
+    command.arg("relink");
+    command.arg("--installation");
+    command.arg(escape(cwd.join(&installation)));
+    if let Some(dir) = options.versions_dir() {
+        command.arg("--versions-dir");
+        command.arg(escape(cwd.join(dir)));
+    }
+    command.arg("--overwrite");
+    command.arg("smart");
+
+    let mut linker = Linker::new(&scripts_dir, base);
+    linker.set_relink(command);
+    linker.set_overwrite(overwrite_policy);
+    linker.set_installation(cwd.join(&installation));
+    if let Some(key) = key()? {
+        linker.set_key(key);
+    }
+    let changes = linker.link(&versions).map_err(|e| {
+        Error::Failure(format!(
+            "failed to link into {}: {}", scripts_dir.display(), e,
+        ))
+    })?;
+
+    let name = |path: &Path| display(path.file_name().unwrap_or_default());
+    if !quiet && !changes.published.is_empty() {
+        println!("Publishing scripts...");
+        for path in &changes.published {
+            println!("  {}", name(path));
+        }
+    }
+    if !quiet && !changes.removed.is_empty() {
+        println!("Cleaning stale scripts...");
+        for path in &changes.removed {
+            println!("  {}", name(path));
+        }
+    }
+    for (path, e) in &changes.failed {
+        eprintln!("WARNING: Failed to update {}.\n{}", name(path), e);
+    }
+    Ok(())
+}
+
+// Whether a file in a shims directory may be a shim.
+fn may_be_shim(path: &Path) -> bool {
+    if !path.is_file() {
+        return false;
+    }
+    match consts::EXE_EXTENSION {
+        "" => true,
+        ext => path.extension().and_then(OsStr::to_str)
+            .is_some_and(|e| e.eq_ignore_ascii_case(ext)),
+    }
+}
+
+// Shims in a directory, sorted, or why they cannot be checked.
+fn shims_in(dir: &Path) -> Vec<(PathBuf, Result<Commands, String>)> {
+    let mut paths: Vec<_> = match fs::read_dir(dir) {
+        Ok(entries) => entries.filter_map(Result::ok)
+            .map(|entry| entry.path())
+            .filter(|path| may_be_shim(path))
+            .collect(),
+        Err(_) => vec![],
+    };
+    paths.sort();
+    paths.into_iter().map(|path| {
+        let commands = match Commands::from_path(&path) {
+            Ok(ref commands) if commands.is_legacy() => {
+                Err(String::from("legacy shim"))
+            },
+            Ok(commands) => Ok(commands),
+            Err(e) => Err(e.to_string()),
+        };
+        (path, commands)
+    }).collect()
+}
+
+fn verify<I>(mut args: I) -> Result<(), Error>
+        where I: Iterator<Item=OsString> {
+    let mut installation = None;
+    let mut shims = vec![];
+    while let Some(arg) = args.next() { match arg.to_str() {
+        Some("--installation") => {
+            let path = PathBuf::from(value(&mut args, "--installation")?);
+            installation = Some(path);
+        },
+        _ => {
+            let path = PathBuf::from(arg);
+            let commands = Commands::from_path(&path).map_err(|e| {
+                Error::Failure(format!(
+                    "failed to read {}: {}", path.display(), e,
+                ))
+            })?;
+            shims.push((path, Ok(commands)));
+        },
+    }}
+    if let Some(installation) = installation {
+        for key in &["cmd_dir", "scripts_dir"] {
+            shims.extend(shims_in(&configured_dir(&installation, key)?));
+        }
+    } else if shims.is_empty() {
+        return Err(Error::Usage(String::from("no shims given")));
+    }
+
+    let mut failures = 0;
+    for (path, commands) in shims {
+        let commands = match commands {
+            Ok(commands) => commands,
+            Err(reason) => {
+                println!(
+                    "{:<10} {} ({})", "unchecked", display(path.as_os_str()),
+                    reason,
+                );
+                continue;
+            },
+        };
+        let placeholders = Placeholders::for_shim(&path, commands.options());
+        for command in commands.expanded(&placeholders) {
+            let exe = match command.exe() {
+                Some(exe) => exe,
+                None => { continue; },
+            };
+            let status = match verify::check(exe, &command) {
+                Status::Matches => "ok",
+                Status::Unrecorded => "unchecked",
+                Status::Changed => "changed",
+                Status::Unreadable(ref e)
+                        if e.kind() == io::ErrorKind::NotFound => "missing",
+                Status::Unreadable(_) => "unreadable",
+            };
+            if status != "ok" && status != "unchecked" {
+                failures += 1;
+            }
+            println!(
+                "{:<10} {} -> {}",
+                status, display(path.as_os_str()), display(exe.as_os_str()),
+            );
+        }
+    }
+    if failures > 0 {
+        return Err(Error::Failure(format!(
+            "{} executable(s) failed verification", failures,
+        )));
+    }
+    Ok(())
+}
+
+fn keygen<I>(args: I) -> Result<(), Error>
+        where I: Iterator<Item=OsString> {
+    let mut force = false;
+    for arg in args { match arg.to_str() {
+        Some("--force") => { force = true; },
+        _ => { return Err(Error::Usage(format!("unexpected {:?}", arg))); },
+    }}
+
+    let path = auth::key_path().ok_or_else(|| {
+        Error::Failure(String::from("cannot locate the key"))
+    })?;
+    if !force && path.exists() {
+        return Err(Error::Failure(format!(
+            "{} exists; replace it with --force", path.display(),
+        )));
+    }
+    let fail = |e: io::Error| Error::Failure(format!(
+        "failed to write {}: {}", path.display(), e,
+    ));
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).map_err(fail)?;
+    }
+    let mut open = fs::OpenOptions::new();
+    open.write(true).truncate(true);
+    if force {
+        open.create(true);
+    } else {
+        open.create_new(true);
+    }
+    #[cfg(unix)] {
+        use std::os::unix::fs::OpenOptionsExt;
+        open.mode(0o600);
+    }
+    let key = auth::generate_key().map_err(fail)?;
+    open.open(&path).and_then(|mut file| file.write_all(&key))
+        .map_err(fail)?;
+    println!("{}", path.display());
+    Ok(())
+}
+
+fn main() {
+    let mut args = env::args_os().skip(1);
+    let result = match args.next() {
+        Some(command) => match command.to_str() {
+            Some("create") => create(args),
+            Some("inspect") => inspect(args),
+            Some("relink") => relink(args),
+            Some("verify") => verify(args),
+            Some("keygen") => keygen(args),
+            Some("-h") | Some("--help") => { println!("{}", USAGE); Ok(()) },
+            _ => Err(Error::Usage(format!("unknown command {:?}", command))),
+        },
+        None => Err(Error::Usage(String::from("missing command"))),
+    };
+    match result {
+        Ok(()) => {},
+        Err(Error::Usage(message)) => {
+            eprintln!("shim-tool: {}\n\n{}", message, USAGE);
+            exit(2);
+        },
+        Err(Error::Failure(message)) => {
+            eprintln!("shim-tool: {}", message);
+            exit(1);
+        },
+    }
+}
+
+
+#[cfg(test)]
+#[allow(dead_code)]
+mod testing;
+
+#[cfg(test)]
+mod tool_test {
+    use std::ffi::OsString;
+    use std::path::{Path, PathBuf};
+    use shim::cmds::{Condition, EnvOp};
+    use testing::TempDir;
+    use super::{parse_create, Error, Spec};
+
+    fn parse(args: &[&str]) -> Result<Spec, Error> {
+        parse_create(args.iter().map(OsString::from))
+    }
+
+    fn usage(args: &[&str]) -> String {
+        match parse(args) {
+            Err(Error::Usage(message)) => message,
+            r => panic!("unexpected {:?}", r),
+        }
+    }
+
+    #[test]
+    fn then() {
+        let spec = parse(&[
+            "--out", "pip.exe", "--cwd", "/w", "--env", "A", "1",
+            "--", "python", "-m", "pip", "--", "--then",
+            "--when", "always", "--detach", "--", "shim-tool", "relink",
+        ]).unwrap();
+        assert_eq!(spec.out, Path::new("pip.exe"));
+        assert_eq!(spec.commands.len(), 2);
+
+        let pip = &spec.commands[0];
+        assert_eq!(pip.exe(), Some(&PathBuf::from("python")));
+        assert_eq!(pip.args(), &vec![
+            OsString::from("-m"), OsString::from("pip"), OsString::from("--"),
+        ]);
+        assert_eq!(pip.working_dir(), Some(&PathBuf::from("/w")));
+        assert_eq!(pip.env(), &[EnvOp::Set("A".into(), "1".into())]);
+        assert_eq!(pip.condition(), Condition::OnSuccess);
+        assert!(!pip.prepends_installation());
+
+        // Options of one command do not carry over to the next.
+        let relink = &spec.commands[1];
+        assert_eq!(relink.exe(), Some(&PathBuf::from("shim-tool")));
+        assert_eq!(relink.args(), &vec![OsString::from("relink")]);
+        assert_eq!(relink.working_dir(), None);
+        assert!(relink.env().is_empty());
+        assert_eq!(relink.condition(), Condition::Always);
+        assert!(relink.detached());
+        assert!(!relink.prepends_installation());
+    }
+
+    #[test]
+    fn interpreters() {
+        let prepends = |args: &[&str]| {
+            let mut full = vec!["--out", "a", "--"];
+            full.extend(args);
+            parse(&full).unwrap().commands[0].prepends_installation()
+        };
+        for exe in &["python", "python3", "py/Python3.7.exe"] {
+            assert!(prepends(&[exe]), "{}", exe);
+        }
+        for args in &[
+            &["python", "-m", "pythonup"][..],
+            &["python", "script.py"],
+            &["python-foo"],
+            &["python3.7.1"],
+            &["pythonw3"],
+            &["pip3"],
+        ] {
+            assert!(!prepends(args), "{:?}", args);
+        }
+
+        // Callers opt in for anything else.
+        let spec = parse(&[
+            "--out", "a", "--install-path", "--", "python", "-m", "pip",
+        ]).unwrap();
+        assert!(spec.commands[0].prepends_installation());
+    }
+
+    #[test]
+    fn option_without_command() {
+        for args in &[
+            &["--out", "a", "--", "python", "--then", "--env", "A", "1"][..],
+            &["--out", "a", "--", "python", "--then", "--keep-path"],
+            &["--out", "a", "--digest", "--launcher"],
+            &["--out", "a", "--when", "always", "--launcher"],
+        ] {
+            assert_eq!(usage(args), "option without command");
+        }
+        let spec = parse(&["--out", "a", "--", "python", "--then"]);
+        assert_eq!(spec.unwrap().commands.len(), 1);
+    }
+
+    #[test]
+    fn script_conflicts() {
+        let dir = TempDir::new("tool-conflicts");
+        // An empty zip archive.
+        let mut zip = b"PK\x05\x06".to_vec();
+        zip.extend(&[0; 18]);
+        let app = dir.write("app.pyz", zip);
+        let app = app.to_str().unwrap();
+        let message = "--zipapp cannot be used with --launcher or --script";
+        assert_eq!(
+            usage(&["--out", "a", "--launcher", "--zipapp", app]), message,
+        );
+        assert_eq!(
+            usage(&["--out", "a", "--zipapp", app, "--script", "s.py"]),
+            message,
+        );
+
+        // A script or zip archive is enough to run.
+        let spec = parse(&["--out", "a", "--zipapp", app]).unwrap();
+        assert!(spec.app.is_some() && spec.commands.is_empty());
+        let spec = parse(&["--out", "a", "--launcher"]).unwrap();
+        assert!(spec.options.script().is_some());
+    }
+
+    #[test]
+    fn bad_usage() {
+        assert_eq!(usage(&["--", "python"]), "missing --out");
+        assert_eq!(usage(&["--out", "a"]), "no commands given");
+        assert_eq!(usage(&["--out", "a", "--"]), "empty command");
+        assert_eq!(usage(&["--out"]), "--out requires a value");
+        let bad = usage(&["--out", "a", "--when", "x"]);
+        assert_eq!(bad, "bad --when \"x\"");
+        let unexpected = usage(&["--out", "a", "python"]);
+        assert_eq!(unexpected, "unexpected \"python\"");
+    }
+}
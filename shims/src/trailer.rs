@@ -0,0 +1,543 @@
+//! Self-describing shim data format.
+//!
+//! Shim data are appended to the end of the executable, followed by a
+//! fixed-size footer (all integers little-endian):
+//!
+//! ```text
+//! +---------+--------------+----------+---------+------------+
+//! | payload | length (u32) | CRC-32   | version | "PYUPSHIM" |
+//! +---------+--------------+----------+---------+------------+
+//! ```
+//!
+//! The payload is a sequence of records, each consisting of a one-byte tag,
+//! a `u32` data length, and the data. See `tags` for known records.
+//!
+//! Readers refuse records with unknown tags, unless the tag is in the
+//! optional range (see `tags::is_optional`), so older shims never run a
+//! command they only partly understand. `VERSION` is bumped whenever a
+//! change would be misread by older readers, e.g. a new tag they must
+//! understand, so they report an unsupported version instead.
+//!
+//! Strings that are not valid Unicode are stored in a platform encoding,
+//! prefixed by a byte identifying it (see `encodings`).
+//!
+//! Files without the magic marker are not in this format; the caller should
+//! fall back to the legacy reversed-text layout, unless a zip archive follows
+//! the data (see `zipapp`).
+
+use std::ffi::{OsStr, OsString};
+use std::io::{self, Read, Seek, SeekFrom};
+
+#[cfg(unix)] use std::os::unix::ffi::{OsStrExt, OsStringExt};
+#[cfg(windows)] use std::os::windows::ffi::{OsStrExt, OsStringExt};
+
+pub const MAGIC: &[u8; 8] = b"PYUPSHIM";
+pub const VERSION: u32 = 1;
+pub const FOOTER_SIZE: u64 = 20;
+
+pub mod tags {
+    /// Start of a new command. No data.
+    pub const COMMAND: u8 = 0x01;
+
+    /// An argument of the current command, encoded in UTF-8. The first
+    /// argument of a command is its executable.
+    pub const ARG: u8 = 0x02;
+
+    /// An argument of the current command, as an encoded OS string. This is
+    /// used in place of `ARG` if the argument is not valid Unicode.
+    pub const OS_ARG: u8 = 0x03;
+
+    /// Path of the current command's executable relative to an installation,
+    /// as an encoded OS string. See `Command::resolve`.
+    pub const RESOLVE: u8 = 0x04;
+
+    /// Directory containing installations, as an encoded OS string. See
+    /// `Options::versions_dir`.
+    pub const VERSIONS_DIR: u8 = 0x05;
+
+    /// Path to pythonup's `installation.json`, as an encoded OS string. See
+    /// `Options::installation`.
+    pub const INSTALLATION: u8 = 0x06;
+
+    /// Version series the current command is for, encoded in UTF-8. See
+    /// `Command::series`.
+    pub const SERIES: u8 = 0x07;
+
+    /// Set an environment variable for the current command. Data are the
+    /// name and value, as a list of encoded OS strings.
+    pub const ENV_SET: u8 = 0x08;
+
+    /// Remove an environment variable for the current command. Data are the
+    /// name, as a list of encoded OS strings.
+    pub const ENV_UNSET: u8 = 0x09;
+
+    /// Prepend an entry to a path-like environment variable for the current
+    /// command. Data are the name and entry, as a list of encoded OS
+    /// strings.
+    pub const ENV_PREPEND_PATH: u8 = 0x0a;
+
+    /// Working directory of the current command, as an encoded OS string.
+    /// See `Command::working_dir`.
+    pub const WORKING_DIR: u8 = 0x0b;
+
+    /// Do not append the shim's own arguments to the current command. No
+    /// data. See `Command::appends_own_args`.
+    pub const NO_OWN_ARGS: u8 = 0x0c;
+
+    /// When the current command runs, as one byte. See `Condition`.
+    pub const CONDITION: u8 = 0x0d;
+
+    /// Which exit code the shim reports, as one byte. See `Report`.
+    pub const REPORT: u8 = 0x0e;
+
+    /// Run the current command in the background. No data. See
+    /// `Command::detached`.
+    pub const DETACHED: u8 = 0x0f;
+
+    /// Directory to watch for changes by the first command, as an encoded OS
+    /// string. See `Options::watch_dir`.
+    pub const WATCH_DIR: u8 = 0x10;
+
+    /// SHA-256 of the current command's executable when the shim was
+    /// written, as 32 bytes. See `Command::exe_digest`.
+    pub const EXE_DIGEST: u8 = 0x11;
+
+    /// What to do if an executable does not match its digest, as one byte.
+    /// See `OnChange`.
+    pub const ON_CHANGE: u8 = 0x12;
+
+    /// HMAC-SHA256 of the payload before this record, keyed with the
+    /// installation's key, as 32 bytes. Only valid as the last record. See
+    /// `auth`.
+    pub const MAC: u8 = 0x13;
+
+    /// How deeply shims may be nested when this one runs, as one byte. See
+    /// `Options::max_depth`.
+    pub const MAX_DEPTH: u8 = 0x14;
+
+    /// Put the current command's installation first in its `PATH`, in
+    /// place of the shim's directory. No data. See
+    /// `Command::prepends_installation`.
+    pub const PREPEND_INSTALLATION: u8 = 0x15;
+
+    /// Script to run in launcher mode, as an encoded OS string. See
+    /// `Options::script`.
+    pub const SCRIPT: u8 = 0x16;
+
+    /// Shebang line to run the script with, instead of its own, as an
+    /// encoded OS string. See `Options::shebang`.
+    pub const SHEBANG: u8 = 0x17;
+
+    /// Size and SHA-256 of a zip archive appended after the shim data, with
+    /// the shebang line before it, as a `u64` and 32 bytes. See `zipapp`.
+    pub const ZIPAPP: u8 = 0x18;
+
+    /// Tags from this one up are optional: readers skip records they do
+    /// not understand in this range, instead of refusing the shim.
+    pub const OPTIONAL: u8 = 0x80;
+
+    pub fn is_optional(tag: u8) -> bool {
+        tag >= OPTIONAL
+    }
+}
+
+pub mod encodings {
+    /// UTF-8.
+    pub const UTF8: u8 = 0x00;
+
+    /// Arbitrary bytes, as used by Unix platforms.
+    pub const BYTES: u8 = 0x01;
+
+    /// UTF-16 code units in little-endian, possibly ill-formed, as used by
+    /// Windows.
+    pub const WIDE: u8 = 0x02;
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Record {
+    pub tag: u8,
+    pub data: Vec<u8>,
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn read_u32(bytes: &[u8]) -> u32 {
+    let mut buf = [0u8; 4];
+    buf.copy_from_slice(&bytes[..4]);
+    u32::from_le_bytes(buf)
+}
+
+/// Encode an OS string, prefixed by the encoding used.
+pub fn encode_os_str(s: &OsStr) -> Vec<u8> {
+    if let Some(s) = s.to_str() {
+        let mut bytes = vec![encodings::UTF8];
+        bytes.extend(s.as_bytes());
+        return bytes;
+    }
+    encode_native_os_str(s)
+}
+
+#[cfg(unix)]
+fn encode_native_os_str(s: &OsStr) -> Vec<u8> {
+    let mut bytes = vec![encodings::BYTES];
+    bytes.extend(s.as_bytes());
+    bytes
+}
+
+#[cfg(windows)]
+fn encode_native_os_str(s: &OsStr) -> Vec<u8> {
+    let mut bytes = vec![encodings::WIDE];
+    for unit in s.encode_wide() {
+        bytes.extend(&unit.to_le_bytes());
+    }
+    bytes
+}
+
+fn decode_wide(bytes: &[u8]) -> io::Result<Vec<u16>> {
+    if !bytes.len().is_multiple_of(2) {
+        return Err(invalid(String::from("odd-sized wide string")));
+    }
+    Ok(bytes.chunks(2).map(|c| u16::from_le_bytes([c[0], c[1]])).collect())
+}
+
+/// Decode an OS string encoded by `encode_os_str`.
+///
+/// An error is returned if the string cannot be represented on the current
+/// platform, e.g. ill-formed UTF-16 on Unix.
+pub fn decode_os_str(data: &[u8]) -> io::Result<OsString> {
+    let (encoding, bytes) = match data.split_first() {
+        Some((encoding, bytes)) => (*encoding, bytes),
+        None => { return Err(invalid(String::from("missing encoding"))); },
+    };
+    match encoding {
+        encodings::UTF8 => String::from_utf8(bytes.to_vec())
+            .map(OsString::from)
+            .map_err(|e| invalid(e.to_string())),
+        encodings::BYTES => decode_native_bytes(bytes),
+        encodings::WIDE => decode_native_wide(&decode_wide(bytes)?),
+        _ => Err(invalid(format!("unknown encoding {:#04x}", encoding))),
+    }
+}
+
+#[cfg(unix)]
+fn decode_native_bytes(bytes: &[u8]) -> io::Result<OsString> {
+    Ok(OsString::from_vec(bytes.to_vec()))
+}
+
+#[cfg(windows)]
+fn decode_native_bytes(bytes: &[u8]) -> io::Result<OsString> {
+    String::from_utf8(bytes.to_vec())
+        .map(OsString::from)
+        .map_err(|e| invalid(e.to_string()))
+}
+
+#[cfg(unix)]
+fn decode_native_wide(units: &[u16]) -> io::Result<OsString> {
+    String::from_utf16(units)
+        .map(OsString::from)
+        .map_err(|e| invalid(e.to_string()))
+}
+
+#[cfg(windows)]
+fn decode_native_wide(units: &[u16]) -> io::Result<OsString> {
+    Ok(OsString::from_wide(units))
+}
+
+/// Encode a list of OS strings, each prefixed by its `u32` length.
+pub fn encode_os_strs(strings: &[&OsStr]) -> Vec<u8> {
+    let mut bytes = vec![];
+    for s in strings {
+        let encoded = encode_os_str(s);
+        bytes.extend(&(encoded.len() as u32).to_le_bytes());
+        bytes.extend(encoded);
+    }
+    bytes
+}
+
+/// Decode a list of OS strings encoded by `encode_os_strs`.
+pub fn decode_os_strs(data: &[u8]) -> io::Result<Vec<OsString>> {
+    let mut strings = vec![];
+    let mut rest = data;
+    while !rest.is_empty() {
+        if rest.len() < 4 {
+            return Err(invalid(String::from("truncated string length")));
+        }
+        let length = read_u32(rest) as usize;
+        rest = &rest[4..];
+        if rest.len() < length {
+            return Err(invalid(String::from("truncated string")));
+        }
+        strings.push(decode_os_str(&rest[..length])?);
+        rest = &rest[length..];
+    }
+    Ok(strings)
+}
+
+/// CRC-32 (IEEE 802.3) of the bytes.
+pub fn checksum(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in bytes {
+        crc ^= u32::from(byte);
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xedb8_8320 & mask);
+        }
+    }
+    !crc
+}
+
+/// Read the payload out of a file.
+///
+/// `None` is returned if the file does not end with a footer. An error is
+/// returned if a footer is found, but the payload cannot be read, or fails
+/// validation.
+pub fn read<R: Read + Seek>(reader: &mut R) -> io::Result<Option<Vec<u8>>> {
+    let size = reader.seek(SeekFrom::End(0))?;
+    read_before(reader, size)
+}
+
+/// Read the payload out of a file, with its footer ending at `size`.
+///
+/// This is for shims with data appended after theirs. See `read`.
+pub fn read_before<R: Read + Seek>(reader: &mut R, size: u64)
+        -> io::Result<Option<Vec<u8>>> {
+    if size < FOOTER_SIZE {
+        return Ok(None);
+    }
+    let mut footer = [0u8; FOOTER_SIZE as usize];
+    reader.seek(SeekFrom::Start(size - FOOTER_SIZE))?;
+    reader.read_exact(&mut footer)?;
+    if &footer[12..] != MAGIC {
+        return Ok(None);
+    }
+
+    let version = read_u32(&footer[8..]);
+    if version != VERSION {
+        return Err(invalid(format!("unsupported shim version {}", version)));
+    }
+    let length = u64::from(read_u32(&footer[0..]));
+    if length > size - FOOTER_SIZE {
+        return Err(invalid(format!("bad shim data length {}", length)));
+    }
+
+    let mut payload = vec![0u8; length as usize];
+    reader.seek(SeekFrom::Start(size - FOOTER_SIZE - length))?;
+    reader.read_exact(&mut payload)?;
+    if checksum(&payload) != read_u32(&footer[4..]) {
+        return Err(invalid(String::from("shim data checksum mismatch")));
+    }
+    Ok(Some(payload))
+}
+
+/// Split a payload into records.
+pub fn parse(payload: &[u8]) -> io::Result<Vec<Record>> {
+    let mut records = vec![];
+    let mut rest = payload;
+    while !rest.is_empty() {
+        if rest.len() < 5 {
+            return Err(invalid(String::from("truncated record header")));
+        }
+        let tag = rest[0];
+        let length = read_u32(&rest[1..]) as usize;
+        rest = &rest[5..];
+        if rest.len() < length {
+            return Err(invalid(format!("truncated record {:#04x}", tag)));
+        }
+        records.push(Record { tag, data: rest[..length].to_vec() });
+        rest = &rest[length..];
+    }
+    Ok(records)
+}
+
+/// Serialize records into a payload.
+///
+/// This is the inverse of `parse`.
+pub fn encode_payload(records: &[Record]) -> Vec<u8> {
+    let mut bytes = vec![];
+    for record in records {
+        bytes.push(record.tag);
+        bytes.extend(&(record.data.len() as u32).to_le_bytes());
+        bytes.extend(&record.data);
+    }
+    bytes
+}
+
+/// Serialize records into a payload, followed by its footer.
+///
+/// This is the inverse of `read` and `parse`; the result should be appended
+/// to an executable.
+pub fn encode(records: &[Record]) -> Vec<u8> {
+    let mut bytes = encode_payload(records);
+    let crc = checksum(&bytes);
+    bytes.extend(&(bytes.len() as u32).to_le_bytes());
+    bytes.extend(&crc.to_le_bytes());
+    bytes.extend(&VERSION.to_le_bytes());
+    bytes.extend(MAGIC);
+    bytes
+}
+
+
+#[cfg(test)]
+mod trailer_test {
+    use std::ffi::{OsStr, OsString};
+    use std::io::Cursor;
+    use super::{
+        checksum, decode_os_str, decode_os_strs, encode, encode_os_str,
+        encode_os_strs, encodings, parse, read, tags, Record, MAGIC,
+    };
+
+    fn footer(payload: &[u8], crc: u32, version: u32) -> Vec<u8> {
+        let mut bytes = vec![];
+        bytes.extend(&(payload.len() as u32).to_le_bytes());
+        bytes.extend(&crc.to_le_bytes());
+        bytes.extend(&version.to_le_bytes());
+        bytes.extend(MAGIC);
+        bytes
+    }
+
+    fn shim(payload: &[u8], crc: u32, version: u32) -> Cursor<Vec<u8>> {
+        let mut bytes = b"MZ\x90\x00".to_vec();
+        bytes.extend(payload);
+        bytes.extend(footer(payload, crc, version));
+        Cursor::new(bytes)
+    }
+
+    #[test]
+    fn crc32() {
+        assert_eq!(checksum(b""), 0);
+        assert_eq!(checksum(b"123456789"), 0xcbf4_3926);
+    }
+
+    #[test]
+    fn read_payload() {
+        let payload = b"\x01\x00\x00\x00\x00";
+        let mut file = shim(payload, checksum(payload), 1);
+        assert_eq!(read(&mut file).unwrap(), Some(payload.to_vec()));
+    }
+
+    #[test]
+    fn read_empty_payload() {
+        let mut file = shim(b"", checksum(b""), 1);
+        assert_eq!(read(&mut file).unwrap(), Some(vec![]));
+    }
+
+    #[test]
+    fn no_magic() {
+        let mut file = Cursor::new(b"MZ\x90\x00\n\nexe.nohtyp\\:C".to_vec());
+        assert_eq!(read(&mut file).unwrap(), None);
+        assert_eq!(read(&mut Cursor::new(vec![])).unwrap(), None);
+    }
+
+    #[test]
+    fn bad_checksum() {
+        let payload = b"\x01\x00\x00\x00\x00";
+        let mut file = shim(payload, checksum(payload) ^ 1, 1);
+        assert!(read(&mut file).is_err());
+    }
+
+    #[test]
+    fn bad_version() {
+        let payload = b"\x01\x00\x00\x00\x00";
+        let mut file = shim(payload, checksum(payload), 99);
+        assert!(read(&mut file).is_err());
+    }
+
+    #[test]
+    fn bad_length() {
+        let mut bytes = footer(&[0; 64], 0, 1);
+        bytes.insert(0, 0);
+        assert!(read(&mut Cursor::new(bytes)).is_err());
+    }
+
+    #[test]
+    fn parse_records() {
+        let records = parse(b"\x01\x00\x00\x00\x00\x02\x03\x00\x00\x00abc");
+        assert_eq!(records.unwrap(), vec![
+            Record { tag: tags::COMMAND, data: vec![] },
+            Record { tag: tags::ARG, data: b"abc".to_vec() },
+        ]);
+    }
+
+    #[test]
+    fn encode_round_trip() {
+        let records = vec![
+            Record { tag: tags::COMMAND, data: vec![] },
+            Record { tag: tags::ARG, data: b"abc".to_vec() },
+        ];
+        let mut file = Cursor::new(encode(&records));
+        let payload = read(&mut file).unwrap().unwrap();
+        assert_eq!(parse(&payload).unwrap(), records);
+    }
+
+    #[test]
+    fn os_str_utf8() {
+        let s = OsString::from("C:\\Users\\Jérôme\\python.exe");
+        let bytes = encode_os_str(&s);
+        assert_eq!(bytes[0], encodings::UTF8);
+        assert_eq!(decode_os_str(&bytes).unwrap(), s);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn os_str_bytes() {
+        use std::os::unix::ffi::OsStringExt;
+
+        // Latin-1 "Jérôme", which is not valid UTF-8.
+        let s = OsString::from_vec(b"/home/J\xe9r\xf4me/python".to_vec());
+        let bytes = encode_os_str(&s);
+        assert_eq!(bytes[0], encodings::BYTES);
+        assert_eq!(decode_os_str(&bytes).unwrap(), s);
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn os_str_wide() {
+        use std::os::windows::ffi::OsStringExt;
+
+        // Unpaired surrogate, which is not valid UTF-16.
+        let s = OsString::from_wide(&[0x0061, 0xd800, 0x0062]);
+        let bytes = encode_os_str(&s);
+        assert_eq!(bytes[0], encodings::WIDE);
+        assert_eq!(decode_os_str(&bytes).unwrap(), s);
+    }
+
+    #[test]
+    fn os_str_foreign_wide() {
+        // Well-formed UTF-16 decodes everywhere.
+        let bytes = b"\x02a\x00\xe9\x00";
+        assert_eq!(decode_os_str(bytes).unwrap(), OsString::from("a\u{e9}"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn os_str_ill_formed_wide() {
+        assert!(decode_os_str(b"\x02a\x00\x00\xd8").is_err());
+    }
+
+    #[test]
+    fn os_str_bad() {
+        assert!(decode_os_str(b"").is_err());
+        assert!(decode_os_str(b"\xffabc").is_err());
+        assert!(decode_os_str(b"\x00\xff").is_err());
+        assert!(decode_os_str(b"\x02a").is_err());
+    }
+
+    #[test]
+    fn os_strs() {
+        let strings = [OsStr::new("PATH"), OsStr::new(""), OsStr::new("a\0b")];
+        let data = encode_os_strs(&strings);
+        assert_eq!(&data[..9], b"\x05\x00\x00\x00\x00PATH");
+        assert_eq!(decode_os_strs(&data).unwrap(), strings);
+        assert_eq!(decode_os_strs(b"").unwrap(), Vec::<OsString>::new());
+        assert!(decode_os_strs(&data[..data.len() - 1]).is_err());
+        assert!(decode_os_strs(b"\x01\x00").is_err());
+    }
+
+    #[test]
+    fn parse_truncated() {
+        assert!(parse(b"\x02\x03\x00\x00\x00ab").is_err());
+        assert!(parse(b"\x02\x03\x00").is_err());
+    }
+}
@@ -0,0 +1,136 @@
+//! Check executables against digests recorded in shims.
+//!
+//! A shim may record the SHA-256 of each command's executable when written.
+//! If the executable changed since, e.g. because a hand-installed Python
+//! replaced the one pythonup installed, the shim warns before running it,
+//! or refuses to, depending on its `OnChange` policy.
+
+use std::io;
+use std::path::Path;
+
+use cmds::{Command, OnChange, Options};
+use digest::sha256_file;
+use errors::Error;
+
+/// Result of checking an executable.
+#[derive(Debug)]
+pub enum Status {
+    /// No digest is recorded for the executable.
+    Unrecorded,
+
+    /// The executable matches its digest.
+    Matches,
+
+    /// The executable does not match its digest.
+    Changed,
+
+    /// The executable cannot be read, e.g. because it is missing.
+    Unreadable(io::Error),
+}
+
+/// Check the executable to run for a command against its digest.
+///
+/// The digest is only for the command's own executable, so others, e.g.
+/// resolved from a pinned version, are not checked.
+pub fn check(exe: &Path, command: &Command) -> Status {
+    let expected = match command.exe_digest() {
+        Some(digest) if command.exe().is_some_and(|e| e == exe) => digest,
+        _ => { return Status::Unrecorded; },
+    };
+    match sha256_file(exe) {
+        Ok(ref digest) if digest == expected => Status::Matches,
+        Ok(_) => Status::Changed,
+        Err(e) => Status::Unreadable(e),
+    }
+}
+
+/// Check the executable before running it, as the shim's policy says.
+///
+/// A warning is printed for a changed executable, unless the shim refuses
+/// to run it, and an error is returned instead. Unreadable executables are
+/// left for launching to report.
+pub fn enforce(exe: &Path, command: &Command, options: &Options)
+        -> Result<(), Error> {
+    match check(exe, command) {
+        Status::Changed => match options.on_change() {
+            OnChange::Warn => {
+                eprintln!(
+                    "WARNING: {} changed since the shim was written.",
+                    exe.display(),
+                );
+                Ok(())
+            },
+            OnChange::Refuse => Err(Error::Changed(exe.to_path_buf())),
+        },
+        _ => Ok(()),
+    }
+}
+
+
+#[cfg(test)]
+mod verify_test {
+    use std::fs;
+    use std::path::{Path, PathBuf};
+    use cmds::{Command, OnChange, Options};
+    use digest::sha256;
+    use super::{check, enforce, Status};
+    use testing::TempDir;
+
+    // A fake executable, and a command recording its digest.
+    fn python(dir: &TempDir) -> (PathBuf, Command) {
+        let exe = dir.write("python.exe", b"MZ python");
+        let mut command = Command::new();
+        command.arg(&exe);
+        command.set_exe_digest(sha256(b"MZ python"));
+        (exe, command)
+    }
+
+    #[test]
+    fn statuses() {
+        let dir = TempDir::new("verify-statuses");
+        let (exe, command) = python(&dir);
+        match check(&exe, &command) {
+            Status::Matches => {},
+            s => panic!("unexpected {:?}", s),
+        }
+        match check(Path::new("/py/other/python"), &command) {
+            Status::Unrecorded => {},
+            s => panic!("unexpected {:?}", s),
+        }
+        let mut unrecorded = Command::new();
+        unrecorded.arg(&exe);
+        match check(&exe, &unrecorded) {
+            Status::Unrecorded => {},
+            s => panic!("unexpected {:?}", s),
+        }
+
+        fs::write(&exe, b"MZ another python").unwrap();
+        match check(&exe, &command) {
+            Status::Changed => {},
+            s => panic!("unexpected {:?}", s),
+        }
+        fs::remove_file(&exe).unwrap();
+        match check(&exe, &command) {
+            Status::Unreadable(_) => {},
+            s => panic!("unexpected {:?}", s),
+        }
+    }
+
+    #[test]
+    fn policies() {
+        let dir = TempDir::new("verify-policies");
+        let (exe, command) = python(&dir);
+        let mut options = Options::default();
+        options.set_on_change(OnChange::Refuse);
+        assert!(enforce(&exe, &command, &options).is_ok());
+
+        fs::write(&exe, b"MZ another python").unwrap();
+        let e = enforce(&exe, &command, &options).unwrap_err();
+        assert_eq!(e.exit_code(), 121);
+        assert!(enforce(&exe, &command, &Options::default()).is_ok());
+
+        // Missing executables are reported when launched instead.
+        fs::remove_file(&exe).unwrap();
+        assert!(enforce(&exe, &command, &options).is_ok());
+    }
+}
@@ -0,0 +1,388 @@
+//! Write shims.
+//!
+//! This is the inverse of `Commands`. A shim is written by copying the base
+//! executable, and appending the serialized commands to it.
+
+use std::ffi::{OsStr, OsString};
+use std::fs;
+use std::io::{self, Cursor};
+use std::path::Path;
+
+use auth;
+use cmds::{Command, Condition, EnvOp, OnChange, Options, Report};
+use trailer::{self, tags, Record};
+use zipapp::{self, Zipapp};
+
+/// Escape a literal string, e.g. a path, to be kept as-is in commands.
+///
+/// Placeholders and environment variables are expanded in commands when the
+/// shim is launched, so special characters are doubled. See `Placeholders`.
+pub fn escape<S: AsRef<OsStr>>(s: S) -> OsString {
+    let s = s.as_ref();
+    match s.to_str() {
+        Some(s) => {
+            let mut escaped = String::with_capacity(s.len());
+            for c in s.chars() {
+                if let '{' | '}' | '%' | '$' = c {
+                    escaped.push(c);
+                }
+                escaped.push(c);
+            }
+            OsString::from(escaped)
+        },
+        // These are not expanded.
+        None => s.to_os_string(),
+    }
+}
+
+// Plain UTF-8 is preferred so the data stay readable in a hex editor.
+fn arg_record(arg: &OsStr) -> Record {
+    match arg.to_str() {
+        Some(arg) => Record { tag: tags::ARG, data: arg.as_bytes().to_vec() },
+        None => os_str_record(tags::OS_ARG, arg),
+    }
+}
+
+fn os_str_record(tag: u8, s: &OsStr) -> Record {
+    Record { tag, data: trailer::encode_os_str(s) }
+}
+
+fn env_record(op: &EnvOp) -> Record {
+    let (tag, data) = match *op {
+        EnvOp::Set(ref name, ref value) => {
+            (tags::ENV_SET, trailer::encode_os_strs(&[name, value]))
+        },
+        EnvOp::Unset(ref name) => {
+            (tags::ENV_UNSET, trailer::encode_os_strs(&[name]))
+        },
+        EnvOp::PrependPath(ref name, ref entry) => {
+            (tags::ENV_PREPEND_PATH, trailer::encode_os_strs(&[name, entry]))
+        },
+    };
+    Record { tag, data }
+}
+
+/// Convert commands into trailer records.
+pub fn records(commands: &[Command], options: &Options)
+        -> io::Result<Vec<Record>> {
+    let mut records = vec![];
+    if let Some(dir) = options.versions_dir() {
+        records.push(os_str_record(tags::VERSIONS_DIR, dir.as_os_str()));
+    }
+    if let Some(path) = options.installation() {
+        records.push(os_str_record(tags::INSTALLATION, path.as_os_str()));
+    }
+    if let Some(dir) = options.watch_dir() {
+        records.push(os_str_record(tags::WATCH_DIR, dir.as_os_str()));
+    }
+    if options.report() != Report::default() {
+        let data = vec![options.report() as u8];
+        records.push(Record { tag: tags::REPORT, data });
+    }
+    if options.on_change() != OnChange::default() {
+        let data = vec![options.on_change() as u8];
+        records.push(Record { tag: tags::ON_CHANGE, data });
+    }
+    if let Some(path) = options.script() {
+        records.push(os_str_record(tags::SCRIPT, path.as_os_str()));
+    }
+    if let Some(line) = options.shebang() {
+        records.push(os_str_record(tags::SHEBANG, line));
+    }
+    if options.has_max_depth() {
+        let data = vec![options.max_depth()];
+        records.push(Record { tag: tags::MAX_DEPTH, data });
+    }
+    if let Some(zipapp) = options.zipapp() {
+        records.push(Record { tag: tags::ZIPAPP, data: zipapp.to_bytes() });
+    }
+    for command in commands {
+        let exe = command.exe().ok_or_else(|| io::Error::new(
+            io::ErrorKind::InvalidInput, "command without executable",
+        ))?;
+        records.push(Record { tag: tags::COMMAND, data: vec![] });
+        records.push(arg_record(exe.as_os_str()));
+        for arg in command.args() {
+            records.push(arg_record(arg));
+        }
+        if let Some(digest) = command.exe_digest() {
+            let data = digest.to_vec();
+            records.push(Record { tag: tags::EXE_DIGEST, data });
+        }
+        for op in command.env() {
+            records.push(env_record(op));
+        }
+        if let Some(dir) = command.working_dir() {
+            records.push(os_str_record(tags::WORKING_DIR, dir.as_os_str()));
+        }
+        if !command.appends_own_args() {
+            records.push(Record { tag: tags::NO_OWN_ARGS, data: vec![] });
+        }
+        if command.condition() != Condition::default() {
+            let data = vec![command.condition() as u8];
+            records.push(Record { tag: tags::CONDITION, data });
+        }
+        if command.detached() {
+            records.push(Record { tag: tags::DETACHED, data: vec![] });
+        }
+        if command.prepends_installation() {
+            let tag = tags::PREPEND_INSTALLATION;
+            records.push(Record { tag, data: vec![] });
+        }
+        if let Some(path) = command.resolve() {
+            records.push(os_str_record(tags::RESOLVE, path.as_os_str()));
+        }
+        if let Some(series) = command.series() {
+            let data = series.as_bytes().to_vec();
+            records.push(Record { tag: tags::SERIES, data });
+        }
+    }
+    Ok(records)
+}
+
+/// Serialize commands into data to append to a base executable.
+pub fn serialize(commands: &[Command], options: &Options)
+        -> io::Result<Vec<u8>> {
+    Ok(trailer::encode(&records(commands, options)?))
+}
+
+/// Serialize commands, signed with the key if one is given.
+pub fn serialize_signed(
+        commands: &[Command], options: &Options, key: Option<&[u8]>)
+        -> io::Result<Vec<u8>> {
+    let mut records = records(commands, options)?;
+    if let Some(key) = key {
+        auth::sign(&mut records, key);
+    }
+    Ok(trailer::encode(&records))
+}
+
+/// Write a shim to `out`, running `commands` when launched.
+///
+/// With a key, the shim is signed to run in an installation with it.
+pub fn write_shim(
+        base: &Path, out: &Path, commands: &[Command], options: &Options,
+        key: Option<&[u8]>) -> io::Result<()> {
+    let mut bytes = fs::read(base)?;
+    bytes.extend(serialize_signed(commands, options, key)?);
+    fs::write(out, bytes)
+}
+
+/// Write a shim to `out` like `write_shim`, running `app` as a zipapp.
+///
+/// `app` is a zip archive, optionally preceded by a shebang line, like
+/// `.pyz` files. `options` must describe it with `Options::set_zipapp`.
+pub fn write_zipapp_shim(
+        base: &Path, out: &Path, commands: &[Command], options: &Options,
+        key: Option<&[u8]>, app: &[u8]) -> io::Result<()> {
+    let invalid = |message| {
+        io::Error::new(io::ErrorKind::InvalidInput, message)
+    };
+    if options.zipapp() != Some(&Zipapp::of(app)) {
+        return Err(invalid("options do not describe the zip archive"));
+    }
+    let mut bytes = fs::read(base)?;
+    bytes.extend(serialize_signed(commands, options, key)?);
+    let data_end = bytes.len() as u64;
+    bytes.extend(app);
+    match zipapp::locate(&mut Cursor::new(&bytes))? {
+        Some(ref location) if location.data_end == data_end => {},
+        _ => { return Err(invalid("not a zip archive")); },
+    }
+    fs::write(out, bytes)
+}
+
+
+#[cfg(test)]
+mod writer_test {
+    use std::ffi::OsStr;
+    use std::io::Cursor;
+    use cmds::{
+        Command, Commands, Condition, EnvOp, OnChange, Options, Placeholders,
+        Report};
+    use super::{escape, serialize, serialize_signed};
+
+    fn command(args: &[&str]) -> Command {
+        let mut command = Command::new();
+        for arg in args {
+            command.arg(String::from(*arg));
+        }
+        command
+    }
+
+    fn round_trip_options(commands: &[Command], options: &Options)
+            -> Commands {
+        let mut bytes = b"MZ\x90\x00".to_vec();
+        bytes.extend(serialize(commands, options).unwrap());
+        Commands::from_reader(&mut Cursor::new(bytes)).unwrap()
+    }
+
+    fn round_trip(commands: &[Command]) -> Vec<Command> {
+        round_trip_options(commands, &Options::default()).collect()
+    }
+
+    #[test]
+    fn single() {
+        let commands = vec![command(&["C:\\python.exe"])];
+        assert_eq!(round_trip(&commands), commands);
+    }
+
+    #[test]
+    fn multiple() {
+        let commands = vec![
+            command(&["C:\\Scripts\\pip.exe"]),
+            command(&[
+                "C:\\python.exe", "-m", "pythonup",
+                "link", "--all", "--overwrite=smart", "--no-user-friendly",
+            ]),
+        ];
+        assert_eq!(round_trip(&commands), commands);
+    }
+
+    #[test]
+    fn special_characters() {
+        // These would be separators in the legacy format.
+        let commands = vec![command(&["C:\\python.exe", "a\nb", "c\0d", ""])];
+        assert_eq!(round_trip(&commands), commands);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn non_utf8() {
+        use std::ffi::OsString;
+        use std::os::unix::ffi::OsStringExt;
+
+        let mut command = Command::new();
+        command.arg(OsString::from_vec(b"/home/J\xe9r\xf4me/python".to_vec()));
+        command.arg(OsString::from_vec(b"caf\xe9.py".to_vec()));
+        command.arg(OsString::from_vec(b"\xff\xfe\x00\n".to_vec()));
+        let commands = vec![command];
+        assert_eq!(round_trip(&commands), commands);
+    }
+
+    #[test]
+    fn empty() {
+        assert_eq!(round_trip(&[]), vec![]);
+    }
+
+    #[test]
+    fn command_without_exe() {
+        assert!(serialize(&[Command::new()], &Options::default()).is_err());
+    }
+
+    #[test]
+    fn env() {
+        let mut command = command(&["C:\\python.exe"]);
+        command.push_env(EnvOp::Set("PYTHONUTF8".into(), "1".into()));
+        command.push_env(EnvOp::Set("EMPTY".into(), "".into()));
+        command.push_env(EnvOp::Unset("PYTHONPATH".into()));
+        command.push_env(EnvOp::PrependPath("PATH".into(), "C:\\bin".into()));
+        let commands = vec![command];
+        assert_eq!(round_trip(&commands), commands);
+    }
+
+    #[test]
+    fn working_dir() {
+        let mut first = command(&["C:\\tool.exe"]);
+        first.set_working_dir("{shim_dir}\\..\\lib");
+        let mut second = command(&["C:\\python.exe"]);
+        second.set_working_dir("C:\\");
+        second.set_prepends_installation(true);
+        second.set_appends_own_args(false);
+        let commands = vec![first, second, command(&["C:\\python.exe"])];
+        assert_eq!(round_trip(&commands), commands);
+    }
+
+    #[test]
+    fn sequencing() {
+        let mut options = Options::default();
+        options.set_report(Report::Worst);
+        options.set_watch_dir("{shim_dir}\\..\\Scripts");
+        options.set_max_depth(4);
+        let mut cleanup = command(&["C:\\cleanup.exe"]);
+        cleanup.set_condition(Condition::OnFailure);
+        let mut relink = command(&["C:\\relink.exe"]);
+        relink.set_condition(Condition::Always);
+        relink.set_detached(true);
+        let commands = vec![command(&["C:\\pip.exe"]), cleanup, relink];
+
+        let result = round_trip_options(&commands, &options);
+        assert_eq!(result.options(), &options);
+        assert_eq!(result.collect::<Vec<_>>(), commands);
+    }
+
+    #[test]
+    fn digest() {
+        let mut options = Options::default();
+        options.set_on_change(OnChange::Refuse);
+        let mut python = command(&["C:\\python.exe"]);
+        python.set_exe_digest([0xab; 32]);
+        let commands = vec![python, command(&["C:\\pip.exe"])];
+
+        let result = round_trip_options(&commands, &options);
+        assert_eq!(result.options(), &options);
+        assert_eq!(result.collect::<Vec<_>>(), commands);
+    }
+
+    #[test]
+    fn launcher() {
+        let mut options = Options::default();
+        options.set_script("{shim_dir}\\{shim_name}-script.py");
+        options.set_shebang("/usr/bin/env python3 -E");
+        let result = round_trip_options(&[], &options);
+        assert_eq!(result.options(), &options);
+    }
+
+    #[test]
+    fn signed() {
+        let commands = vec![command(&["C:\\python.exe"])];
+        let options = Options::default();
+        let mut bytes = b"MZ\x90\x00".to_vec();
+        let data = serialize_signed(&commands, &options, Some(b"key"));
+        bytes.extend(data.unwrap());
+
+        let read = |bytes: &[u8], key: Option<&[u8]>| {
+            Commands::from_signed_reader(&mut Cursor::new(bytes), key)
+        };
+        let result = read(&bytes, Some(b"key")).unwrap();
+        assert_eq!(result.collect::<Vec<_>>(), commands);
+        assert_eq!(read(&bytes, None).unwrap().collect::<Vec<_>>(), commands);
+        assert!(read(&bytes, Some(b"other key")).is_err());
+
+        let mut unsigned = b"MZ\x90\x00".to_vec();
+        unsigned.extend(serialize(&commands, &options).unwrap());
+        assert!(read(&unsigned, Some(b"key")).is_err());
+        let legacy = b"MZ\x90\x00\n\nexe.nohtyp\\:C";
+        assert!(read(legacy, Some(b"key")).is_err());
+        assert!(read(legacy, None).is_ok());
+    }
+
+    #[test]
+    fn escaped() {
+        let placeholders = Placeholders::default();
+        for s in &[
+            "C:\\100%\\%PATH%\\${HOME}\\{shim_dir}",
+            "{{x}} }} %% $$ $ {",
+            "",
+        ] {
+            let escaped = escape(s);
+            assert_eq!(placeholders.expand(&escaped), OsStr::new(s));
+        }
+        assert_eq!(escape("%A%{b}$"), "%%A%%{{b}}$$");
+    }
+
+    #[test]
+    fn resolve() {
+        let mut options = Options::default();
+        options.set_versions_dir("/opt/pythonup/versions");
+        options.set_installation("/opt/pythonup/lib/installation.json");
+        let mut pip = command(&["/usr/bin/pip3"]);
+        pip.set_resolve("bin/pip");
+        pip.set_series("3");
+        let commands = vec![pip, command(&["/usr/bin/python3", "-V"])];
+
+        let result = round_trip_options(&commands, &options);
+        assert_eq!(result.options(), &options);
+        assert_eq!(result.collect::<Vec<_>>(), commands);
+    }
+}
@@ -0,0 +1,362 @@
+//! Zip archives appended to shims, run by Python as zipapps.
+//!
+//! Like distlib's launchers, a shim may end with a zip archive, optionally
+//! preceded by a shebang line, after its data:
+//!
+//! ```text
+//! +------------+-----------+--------------+-------------+
+//! | executable | shim data | #!shebang\n  | zip archive |
+//! +------------+-----------+--------------+-------------+
+//! ```
+//!
+//! Python only finds archives ending the file, so the shim data are found
+//! before the archive's first entry instead, located through its central
+//! directory. The shim runs the interpreter with its own path as the script,
+//! and Python's zipimport runs the archive's `__main__.py`.
+//!
+//! Archives with comments, or in the ZIP64 format, are not supported.
+
+use std::ffi::OsString;
+use std::io::{self, Read, Seek, SeekFrom};
+
+use digest::{Sha256, DIGEST_SIZE};
+use shebang;
+use trailer::MAGIC;
+
+const END_SIGNATURE: &[u8; 4] = b"PK\x05\x06";
+const END_SIZE: u64 = 22;
+const ENTRY_SIGNATURE: &[u8; 4] = b"PK\x01\x02";
+const ENTRY_SIZE: usize = 46;
+
+// Longest shebang line before an archive.
+const MAX_LINE: u64 = 4096;
+
+/// Size and SHA-256 of the data appended after a shim's data.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Zipapp {
+    pub size: u64,
+    pub digest: [u8; DIGEST_SIZE],
+}
+
+impl Zipapp {
+    pub fn of(data: &[u8]) -> Self {
+        let mut hasher = Sha256::new();
+        hasher.update(data);
+        Zipapp { size: data.len() as u64, digest: hasher.finish() }
+    }
+
+    /// Encode as the data of a `ZIPAPP` record.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut data = self.size.to_le_bytes().to_vec();
+        data.extend(&self.digest);
+        data
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Option<Self> {
+        if data.len() != 8 + DIGEST_SIZE {
+            return None;
+        }
+        let mut size = [0; 8];
+        size.copy_from_slice(&data[..8]);
+        let mut digest = [0; DIGEST_SIZE];
+        digest.copy_from_slice(&data[8..]);
+        Some(Zipapp { size: u64::from_le_bytes(size), digest })
+    }
+}
+
+/// Where an archive appended to a shim is.
+#[derive(Debug, PartialEq)]
+pub struct Location {
+    /// End of the shim data, where the appended data start.
+    pub data_end: u64,
+
+    /// Start of the archive's first entry.
+    pub archive_start: u64,
+
+    /// Shebang line between them, without its line ending.
+    pub shebang: Option<OsString>,
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn read_u16(bytes: &[u8]) -> u16 {
+    u16::from_le_bytes([bytes[0], bytes[1]])
+}
+
+fn read_u32(bytes: &[u8]) -> u32 {
+    u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+}
+
+fn read_at<R: Read + Seek>(reader: &mut R, start: u64, len: u64)
+        -> io::Result<Vec<u8>> {
+    let mut bytes = vec![0u8; len as usize];
+    reader.seek(SeekFrom::Start(start))?;
+    reader.read_exact(&mut bytes)?;
+    Ok(bytes)
+}
+
+/// Find the start of a zip archive ending the file.
+///
+/// `None` is returned if the file does not end with an archive's end of
+/// central directory record. Offsets in the archive may be relative to its
+/// start, or to the file's, so its first entry is looked up.
+pub fn archive_start<R: Read + Seek>(reader: &mut R)
+        -> io::Result<Option<u64>> {
+    let size = reader.seek(SeekFrom::End(0))?;
+    if size < END_SIZE {
+        return Ok(None);
+    }
+    let end = read_at(reader, size - END_SIZE, END_SIZE)?;
+    if &end[..4] != END_SIGNATURE || read_u16(&end[20..]) != 0 {
+        return Ok(None);
+    }
+
+    let entries = read_u16(&end[10..]);
+    let directory_size = u64::from(read_u32(&end[12..]));
+    let directory_offset = u64::from(read_u32(&end[16..]));
+    let directory_start = (size - END_SIZE).checked_sub(directory_size)
+        .ok_or_else(|| invalid("bad zip central directory size"))?;
+    let base = directory_start.checked_sub(directory_offset)
+        .ok_or_else(|| invalid("bad zip central directory offset"))?;
+
+    let directory = read_at(reader, directory_start, directory_size)?;
+    let mut first = directory_offset;
+    let mut pos = 0;
+    for _ in 0..entries {
+        let entry = directory.get(pos..pos + ENTRY_SIZE)
+            .filter(|entry| &entry[..4] == ENTRY_SIGNATURE)
+            .ok_or_else(|| invalid("bad zip central directory entry"))?;
+        first = first.min(u64::from(read_u32(&entry[42..])));
+        pos += ENTRY_SIZE + usize::from(read_u16(&entry[28..]))
+            + usize::from(read_u16(&entry[30..]))
+            + usize::from(read_u16(&entry[32..]));
+    }
+    Ok(Some(base + first))
+}
+
+/// Locate an archive appended after shim data.
+///
+/// `None` is returned if the file does not end with an archive, or no shim
+/// data end right before it, or before a shebang line before it.
+pub fn locate<R: Read + Seek>(reader: &mut R)
+        -> io::Result<Option<Location>> {
+    let archive_start = match archive_start(reader)? {
+        Some(start) => start,
+        None => { return Ok(None); },
+    };
+    let window_start = archive_start.saturating_sub(MAX_LINE);
+    let window = read_at(reader, window_start, archive_start - window_start)?;
+    let data_end = match window.windows(MAGIC.len())
+            .rposition(|bytes| bytes == MAGIC) {
+        Some(pos) => pos + MAGIC.len(),
+        None => { return Ok(None); },
+    };
+
+    let line = &window[data_end..];
+    let shebang = if line.is_empty() {
+        None
+    } else {
+        let line = line.strip_suffix(b"\n")
+            .filter(|line| line.starts_with(b"#!") && !line.contains(&b'\n'))
+            .ok_or_else(|| invalid("bad data before zip archive"))?;
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        Some(shebang::os_string(line))
+    };
+    Ok(Some(Location {
+        data_end: window_start + data_end as u64,
+        archive_start,
+        shebang,
+    }))
+}
+
+/// Describe the data from `start` to the end of the file.
+pub fn appended<R: Read + Seek>(reader: &mut R, start: u64)
+        -> io::Result<Zipapp> {
+    let size = reader.seek(SeekFrom::End(0))?;
+    reader.seek(SeekFrom::Start(start))?;
+    let mut hasher = Sha256::new();
+    let mut buf = [0u8; 8192];
+    loop {
+        match reader.read(&mut buf)? {
+            0 => { break; },
+            n => hasher.update(&buf[..n]),
+        }
+    }
+    Ok(Zipapp { size: size - start, digest: hasher.finish() })
+}
+
+
+#[cfg(test)]
+mod zipapp_test {
+    use std::ffi::OsString;
+    use std::io::Cursor;
+    use cmds::{Command, Commands, Options};
+    use trailer::{checksum, encode, tags, Record};
+    use writer::serialize_signed;
+    use super::{appended, archive_start, locate, Location, Zipapp};
+
+    // Build a zip archive of stored files, like `zipfile` would. Offsets
+    // are relative to the archive, plus `offset`.
+    fn zip(files: &[(&str, &[u8])], offset: u32) -> Vec<u8> {
+        let mut archive = vec![];
+        let mut directory = vec![];
+        for &(name, data) in files {
+            let mut header = vec![];
+            header.extend(&[0x14, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+            header.extend(&checksum(data).to_le_bytes());
+            header.extend(&(data.len() as u32).to_le_bytes());
+            header.extend(&(data.len() as u32).to_le_bytes());
+            header.extend(&(name.len() as u16).to_le_bytes());
+            header.extend(&[0, 0]);
+
+            directory.extend(b"PK\x01\x02\x14\x00");
+            directory.extend(&header);
+            directory.extend(&[0; 10]);
+            directory.extend(&(offset + archive.len() as u32).to_le_bytes());
+            directory.extend(name.as_bytes());
+
+            archive.extend(b"PK\x03\x04");
+            archive.extend(&header);
+            archive.extend(name.as_bytes());
+            archive.extend(data);
+        }
+        let directory_offset = offset + archive.len() as u32;
+        archive.extend(&directory);
+        archive.extend(b"PK\x05\x06\x00\x00\x00\x00");
+        archive.extend(&(files.len() as u16).to_le_bytes());
+        archive.extend(&(files.len() as u16).to_le_bytes());
+        archive.extend(&(directory.len() as u32).to_le_bytes());
+        archive.extend(&directory_offset.to_le_bytes());
+        archive.extend(&[0, 0]);
+        archive
+    }
+
+    fn files() -> Vec<(&'static str, &'static [u8])> {
+        vec![
+            ("__main__.py", b"import tool\ntool.main()\n"),
+            ("tool.py", b"def main():\n    print('hi')\n"),
+        ]
+    }
+
+    // A shim with its data, then `appended`.
+    fn shim(appended: &[u8]) -> (Vec<u8>, u64) {
+        let mut bytes = b"MZ\x90\x00".to_vec();
+        bytes.extend(encode(&[Record { tag: tags::COMMAND, data: vec![] }]));
+        let data_end = bytes.len() as u64;
+        bytes.extend(appended);
+        (bytes, data_end)
+    }
+
+    #[test]
+    fn archives() {
+        let archive = zip(&files(), 0);
+        let start = archive_start(&mut Cursor::new(&archive)).unwrap();
+        assert_eq!(start, Some(0));
+
+        // Offsets may be relative to the archive, or to the file.
+        let mut prefixed = b"prefix".to_vec();
+        prefixed.extend(&archive);
+        let start = archive_start(&mut Cursor::new(&prefixed)).unwrap();
+        assert_eq!(start, Some(6));
+        let mut prefixed = b"prefix".to_vec();
+        prefixed.extend(zip(&files(), 6));
+        let start = archive_start(&mut Cursor::new(&prefixed)).unwrap();
+        assert_eq!(start, Some(6));
+
+        let empty = zip(&[], 0);
+        assert_eq!(archive_start(&mut Cursor::new(&empty)).unwrap(), Some(0));
+    }
+
+    #[test]
+    fn not_archives() {
+        for bytes in &[&b""[..], b"MZ\x90\x00", b"PK\x05\x06"] {
+            assert_eq!(archive_start(&mut Cursor::new(bytes)).unwrap(), None);
+        }
+
+        // Comments are not supported.
+        let mut commented = zip(&files(), 0);
+        let len = commented.len();
+        commented[len - 2] = 1;
+        commented.push(b'x');
+        let start = archive_start(&mut Cursor::new(&commented)).unwrap();
+        assert_eq!(start, None);
+
+        let mut truncated = zip(&files(), 0);
+        truncated.drain(..40);
+        assert!(archive_start(&mut Cursor::new(&truncated)).is_err());
+    }
+
+    #[test]
+    fn appended_archive() {
+        let archive = zip(&files(), 0);
+        let (bytes, data_end) = shim(&archive);
+        assert_eq!(locate(&mut Cursor::new(&bytes)).unwrap(), Some(Location {
+            data_end, archive_start: data_end, shebang: None,
+        }));
+        let zipapp = appended(&mut Cursor::new(&bytes), data_end).unwrap();
+        assert_eq!(zipapp, Zipapp::of(&archive));
+        assert_eq!(Zipapp::from_bytes(&zipapp.to_bytes()), Some(zipapp));
+    }
+
+    #[test]
+    fn shebang_line() {
+        let mut pyz = b"#!/usr/bin/env python3\r\n".to_vec();
+        pyz.extend(zip(&files(), 0));
+        let (bytes, data_end) = shim(&pyz);
+        let location = locate(&mut Cursor::new(&bytes)).unwrap().unwrap();
+        assert_eq!(location.data_end, data_end);
+        assert_eq!(location.archive_start, data_end + 24);
+        assert_eq!(
+            location.shebang, Some(OsString::from("#!/usr/bin/env python3")),
+        );
+
+        let mut garbage = b"not a shebang\n".to_vec();
+        garbage.extend(zip(&files(), 0));
+        let (bytes, _) = shim(&garbage);
+        assert!(locate(&mut Cursor::new(&bytes)).is_err());
+    }
+
+    #[test]
+    fn without_shim_data() {
+        let mut bytes = b"MZ\x90\x00".to_vec();
+        bytes.extend(zip(&files(), 4));
+        assert_eq!(locate(&mut Cursor::new(&bytes)).unwrap(), None);
+    }
+
+    #[test]
+    fn commands() {
+        let mut pyz = b"#!python3.7\n".to_vec();
+        pyz.extend(zip(&files(), 0));
+        let mut options = Options::default();
+        options.set_zipapp(Zipapp::of(&pyz));
+        let mut command = Command::new();
+        command.arg("C:\\cleanup.exe");
+        let commands = [command];
+        let read = |key: Option<&[u8]>, pyz: &[u8]| {
+            let mut bytes = b"MZ\x90\x00".to_vec();
+            bytes.extend(serialize_signed(&commands, &options, key).unwrap());
+            bytes.extend(pyz);
+            Commands::from_signed_reader(&mut Cursor::new(bytes), key)
+        };
+
+        let read_commands = read(None, &pyz).unwrap();
+        let options = read_commands.options();
+        assert_eq!(options.zipapp(), Some(&Zipapp::of(&pyz)));
+        assert_eq!(options.shebang(), Some(&OsString::from("#!python3.7")));
+        assert_eq!(read_commands.as_slice(), &commands[..]);
+        assert!(read(Some(b"key"), &pyz).is_ok());
+
+        // The archive is checked against the data, and its signature.
+        let mut other = b"#!python3.6\n".to_vec();
+        other.extend(zip(&files(), 0));
+        assert!(read(None, &other).is_ok());
+        let e = read(Some(b"key"), &other).err().unwrap();
+        assert_eq!(e.exit_code(), 120);
+        let mut other = b"#!/usr/bin/env python3\n".to_vec();
+        other.extend(zip(&files(), 0));
+        let e = read(None, &other).err().unwrap();
+        assert_eq!(e.exit_code(), 125);
+    }
+}
@@ -0,0 +1,81 @@
+//! Detached commands, run through the shim binary.
+#![cfg(unix)]
+
+extern crate shim;
+
+#[allow(dead_code)]
+#[path = "../src/testing.rs"]
+mod testing;
+
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::process;
+use std::thread;
+use std::time::Duration;
+
+use shim::cmds::{Command, Condition, Options};
+use shim::writer::write_shim;
+use testing::TempDir;
+
+// A command running a shell script.
+fn script(path: &Path, script: &str) -> Command {
+    fs::write(path, format!("#!/bin/sh\n{}\n", script)).unwrap();
+    fs::set_permissions(path, fs::Permissions::from_mode(0o755)).unwrap();
+    let mut command = Command::new();
+    command.arg(path);
+    command
+}
+
+// Wait for the file to exist, for 10 seconds at most.
+fn wait_for(path: &Path) {
+    for _ in 0..1000 {
+        if path.exists() {
+            return;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    panic!("{} not created", path.display());
+}
+
+#[test]
+fn queued_once() {
+    let dir = TempDir::new("detach-queued");
+    let log = dir.join("log");
+    let started = dir.join("started");
+    let release = dir.join("release");
+
+    let pip = script(&dir.join("pip"), "exit 3");
+    let mut relink = script(&dir.join("relink"), &format!(
+        "touch '{}'\n\
+         while [ ! -e '{}' ]; do sleep 0.01; done\n\
+         echo relink >> '{}'",
+        started.display(), release.display(), log.display(),
+    ));
+    relink.set_condition(Condition::Always);
+    relink.set_detached(true);
+
+    let shim = dir.join("pip3");
+    let base = Path::new(env!("CARGO_BIN_EXE_shim"));
+    let options = Options::default();
+    write_shim(base, &shim, &[pip, relink], &options, None).unwrap();
+    fs::set_permissions(&shim, fs::Permissions::from_mode(0o755)).unwrap();
+    let run = || process::Command::new(&shim).status().unwrap().code();
+
+    // The shim does not wait for, or report the detached command.
+    assert_eq!(run(), Some(3));
+    wait_for(&started);
+
+    // Runs meanwhile are queued as one more run.
+    assert_eq!(run(), Some(3));
+    assert_eq!(run(), Some(3));
+    fs::write(&release, b"").unwrap();
+    for _ in 0..1000 {
+        if fs::read_to_string(&log).unwrap_or_default().lines().count() > 1 {
+            break;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    thread::sleep(Duration::from_millis(200));
+    assert_eq!(fs::read_to_string(&log).unwrap(), "relink\nrelink\n");
+}
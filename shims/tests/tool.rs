@@ -0,0 +1,125 @@
+//! shim-tool, run as a program.
+
+extern crate serde_json;
+
+#[allow(dead_code)]
+#[path = "../src/testing.rs"]
+mod testing;
+
+use std::process::{Command, Output};
+
+use testing::TempDir;
+
+fn shim_tool(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_shim-tool")).args(args).output().unwrap()
+}
+
+#[test]
+fn create_and_inspect() {
+    let dir = TempDir::new("tool-create");
+    let base = dir.write("base", "MZ");
+    let out = dir.join("pip.exe");
+    let (base, out) = (base.to_str().unwrap(), out.to_str().unwrap());
+
+    let output = shim_tool(&[
+        "create", "--base", base, "--out", out, "--report", "worst",
+        "--", "python", "-m", "pip",
+        "--then", "--when", "always", "--detach", "--", "shim-tool", "relink",
+    ]);
+    assert!(output.status.success());
+
+    let output = shim_tool(&["inspect", "--json", out]);
+    assert!(output.status.success());
+    let value: serde_json::Value = serde_json::from_slice(&output.stdout)
+        .unwrap();
+    assert_eq!(value["report"], "worst");
+    let commands = value["commands"].as_array().unwrap();
+    assert_eq!(commands.len(), 2);
+    assert_eq!(commands[0]["exe"], "python");
+    assert_eq!(commands[0]["args"], serde_json::json!(["-m", "pip"]));
+    assert_eq!(commands[1]["exe"], "shim-tool");
+    assert_eq!(commands[1]["when"], "always");
+    assert_eq!(commands[1]["detached"], true);
+}
+
+#[test]
+fn usage_errors() {
+    let output = shim_tool(&[
+        "create", "--out", "a", "--", "python", "--then", "--detach",
+    ]);
+    assert_eq!(output.status.code(), Some(2));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.starts_with("shim-tool: option without command\n"));
+
+    let output = shim_tool(&["nonexistent"]);
+    assert_eq!(output.status.code(), Some(2));
+}
+
+#[cfg(unix)]
+#[test]
+fn signed_installation() {
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+    use std::path::Path;
+    use std::process::Stdio;
+
+    const KEY_ENV: &str = "PYTHONUP_SHIM_KEY";
+
+    let dir = TempDir::new("tool-signed");
+    let config = dir.mkdir("config");
+    let command = |program: &Path| {
+        let mut command = Command::new(program);
+        command.env("XDG_CONFIG_HOME", &config).env_remove(KEY_ENV)
+            .stderr(Stdio::null());
+        command
+    };
+    let tool = |args: &[&str]| {
+        command(Path::new(env!("CARGO_BIN_EXE_shim-tool"))).args(args)
+            .status().unwrap().code()
+    };
+    let run = |name: &str, args: &[&str]| {
+        let out = dir.join(name);
+        let mut create = vec![
+            "create", "--base", env!("CARGO_BIN_EXE_shim"),
+            "--out", out.to_str().unwrap(),
+        ];
+        create.extend(args);
+        create.extend(&["--", "true"]);
+        assert_eq!(tool(&create), Some(0));
+        let mode = fs::Permissions::from_mode(0o755);
+        fs::set_permissions(&out, mode).unwrap();
+        command(&out).status().unwrap().code()
+    };
+
+    assert_eq!(run("before", &[]), Some(0));
+    assert_eq!(tool(&["keygen"]), Some(0));
+    assert!(config.join("pythonup/shim.key").is_file());
+    assert_eq!(tool(&["keygen"]), Some(1));
+    assert_eq!(command(&dir.join("before")).status().unwrap().code(),
+               Some(120));
+
+    let installation = dir.write("installation.json", "{}");
+    let installation = installation.to_str().unwrap();
+    let required = ["--installation", installation];
+    let signed = ["--installation", installation, "--sign"];
+    assert_eq!(run("unsigned", &required), Some(120));
+    assert_eq!(run("signed", &signed), Some(0));
+
+    // The key is not looked up through the shim's data, so dropping the
+    // installation, or writing legacy data, does not skip it.
+    assert_eq!(run("elsewhere", &[]), Some(120));
+    assert_eq!(run("signed-elsewhere", &["--sign"]), Some(0));
+    let legacy = dir.join("legacy");
+    let mut data = fs::read(env!("CARGO_BIN_EXE_shim")).unwrap();
+    data.extend(b"\n\neurt");
+    fs::write(&legacy, data).unwrap();
+    fs::set_permissions(&legacy, fs::Permissions::from_mode(0o755))
+        .unwrap();
+    assert_eq!(command(&legacy).status().unwrap().code(), Some(120));
+
+    // A key named explicitly must exist.
+    let missing = dir.join("missing.key");
+    let status = command(&dir.join("signed")).env(KEY_ENV, &missing)
+        .status().unwrap();
+    assert_eq!(status.code(), Some(120));
+}